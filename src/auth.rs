@@ -0,0 +1,40 @@
+use worker::{Env, Request, Response, Result};
+
+const ADMIN_TOKEN_SECRET: &str = "ADMIN_TOKEN";
+
+/// Compare in constant time so the token can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether the request carries `Authorization: Bearer <ADMIN_TOKEN>`.
+///
+/// When the `ADMIN_TOKEN` secret isn't set, nothing is authorized.
+pub fn is_authorized(req: &Request, env: &Env) -> Result<bool> {
+    let Ok(token) = env.secret(ADMIN_TOKEN_SECRET) else {
+        tracing::warn!("{ADMIN_TOKEN_SECRET} secret is not set, rejecting guarded route");
+        return Ok(false);
+    };
+    let token = token.to_string();
+
+    let provided = req.headers().get("Authorization")?.unwrap_or_default();
+    let provided = provided.strip_prefix("Bearer ").unwrap_or_default();
+
+    Ok(!token.is_empty() && constant_time_eq(provided.as_bytes(), token.as_bytes()))
+}
+
+/// Returns a 401 response when the request isn't authorized, `None` otherwise.
+///
+/// Meant to be used at the top of guarded handlers:
+/// `if let Some(res) = auth::reject_unauthorized(&req, &ctx.env)? { return Ok(res); }`
+pub fn reject_unauthorized(req: &Request, env: &Env) -> Result<Option<Response>> {
+    if is_authorized(req, env)? {
+        Ok(None)
+    } else {
+        Ok(Some(Response::error("Unauthorized", 401)?))
+    }
+}
@@ -0,0 +1,74 @@
+use worker::{Cache, Request, Response, Result, RouteContext};
+
+use crate::AppData;
+
+fn url_param(req: &Request) -> Result<Option<String>> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "url")
+        .map(|(_, v)| v.to_string()))
+}
+
+/// Report whether `?url=` is currently held in the worker Cache used by `fetcher::Client`
+pub async fn cache_status(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let Some(url) = url_param(&req)? else {
+        return Response::error("url key empty", 400);
+    };
+
+    match Cache::default().get(&url, false).await? {
+        Some(cached) => {
+            let headers = cached.headers();
+            Response::from_json(&serde_json::json!({
+                "url": url,
+                "cached": true,
+                "status": cached.status_code(),
+                "cache_control": headers.get("Cache-Control")?,
+                "age": headers.get("Age")?,
+                "date": headers.get("Date")?,
+            }))
+        }
+        None => Response::from_json(&serde_json::json!({
+            "url": url,
+            "cached": false,
+        })),
+    }
+}
+
+/// Drop `?url=` from the worker Cache, so a bad cached page is refetched
+/// instead of sticking around until its TTL runs out
+pub async fn cache_purge(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    if req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "all" && v == "1")
+    {
+        // The Cache API only looks up by URL, there's no listing to purge from
+        return Response::error(
+            "The worker Cache can't be enumerated, purge by `url` or from the Cloudflare dashboard",
+            501,
+        );
+    }
+
+    let Some(url) = url_param(&req)? else {
+        return Response::error("url key empty", 400);
+    };
+
+    let purged = matches!(
+        Cache::default().delete(&url, false).await?,
+        worker::CacheDeletionOutcome::Success
+    );
+
+    Response::from_json(&serde_json::json!({
+        "url": url,
+        "purged": purged,
+    }))
+}
@@ -0,0 +1,63 @@
+use std::io::Write;
+
+use flate2::{Compression, write::GzEncoder};
+use worker::{EncodeBody, Request, Response, ResponseBody, Result};
+
+/// Bodies smaller than this aren't worth compressing
+const MIN_COMPRESS_BYTES: usize = 4 * 1024;
+
+fn accepts_gzip(req: &Request) -> Result<bool> {
+    Ok(req
+        .headers()
+        .get("Accept-Encoding")?
+        .is_some_and(|x| x.split(',').any(|x| x.trim().starts_with("gzip"))))
+}
+
+/// Gzip a fully buffered text response when the client accepts it.
+///
+/// Streamed bodies, small bodies and already encoded or non-text responses are
+/// returned untouched.
+pub fn finalize(req: &Request, res: Response) -> Result<Response> {
+    if !accepts_gzip(req)? || res.headers().get("Content-Encoding")?.is_some() {
+        return Ok(res);
+    }
+
+    let is_text = res
+        .headers()
+        .get("Content-Type")?
+        .is_some_and(|x| x.starts_with("text/") || x.contains("json") || x.contains("xml"));
+    let body = match res.body() {
+        ResponseBody::Body(b) if is_text && b.len() >= MIN_COMPRESS_BYTES => b,
+        _ => return Ok(res),
+    };
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(body)
+        .and_then(|_| encoder.flush())
+        .map_err(|e| worker::Error::RustError(format!("Failed compressing body: {e}")))?;
+    let compressed = encoder
+        .finish()
+        .map_err(|e| worker::Error::RustError(format!("Failed compressing body: {e}")))?;
+
+    tracing::debug!(
+        "Compressed body {} -> {} bytes",
+        body.len(),
+        compressed.len()
+    );
+    // Their ratio over time is what gzip actually saves, see `/metrics`
+    crate::metrics::incr_by("gzip_bytes_in_total", body.len() as u64);
+    crate::metrics::incr_by("gzip_bytes_out_total", compressed.len() as u64);
+
+    let mut headers = res.headers().clone();
+    headers.set("Content-Encoding", "gzip")?;
+    headers.set("Vary", "Accept-Encoding")?;
+    headers.delete("Content-Length")?;
+
+    // Manual, or the runtime would encode the already gzipped body again
+    Ok(Response::builder()
+        .with_status(res.status_code())
+        .with_headers(headers)
+        .with_encode_body(EncodeBody::Manual)
+        .fixed(compressed))
+}
@@ -0,0 +1,150 @@
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+use worker::KvStore;
+
+use crate::discord::DiscordConfig;
+use crate::playlistconfig::PlaylistConfig;
+use crate::urlguard::UrlGuard;
+
+/// Read a `config_*` KV value and parse it. Missing or blank keys give `None`.
+pub async fn get_parsed<T>(kv: &KvStore, key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = kv
+        .get(&crate::kvprefix::key(key))
+        .text()
+        .await
+        .map_err(|e| anyhow!("Failed to get kv: {e:?}"))?;
+
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("Invalid value for `{key}`: {e}")),
+    }
+}
+
+/// A positive integer env var
+fn env_usize(env: &worker::Env, name: &str) -> Option<usize> {
+    env.var(name)
+        .ok()
+        .and_then(|x| x.to_string().parse::<usize>().ok())
+        .filter(|x| *x > 0)
+}
+
+/// Everything the worker is configured with, from env vars, secrets and the
+/// `config_*` KV keys, read and validated together.
+///
+/// Load it once per invocation: route handlers get it through
+/// `AppData::config`, the cron job loads it before running. Only
+/// `KV_PREFIX` and `SUBREQUEST_CONCURRENCY` stay outside, as they have to be
+/// in place before anything (this included) touches KV or fetches.
+pub struct AppConfig {
+    /// `config_playlist`, with secret headers resolved. `None` until it's set.
+    /// A broken one fails only what needs it, see [`AppConfig::playlist`].
+    playlist: std::result::Result<Option<PlaylistConfig>, String>,
+    /// The cron job's `config_*` keys
+    pub discord: DiscordConfig,
+    /// `DISCORD_CHANNEL_IDS`, empty when the secret isn't set
+    pub discord_channels: Vec<String>,
+    /// `DISCORD_SUBREQUEST_BUDGET`
+    pub discord_request_budget: usize,
+    /// `DISCORD_CHANNEL_CONCURRENCY`: channels the cron job fetches at once
+    pub discord_channel_concurrency: usize,
+    /// `KV_BULK_KEY_LIMIT`: keys a bulk KV route processes per request
+    pub kv_bulk_key_limit: usize,
+    /// `config_fetch_blocked_hosts`
+    pub url_guard: UrlGuard,
+    /// `config_template_text`: minijinja source replacing the plain text page
+    pub text_template: Option<String>,
+    /// `config_get_forward_headers`: request headers `/get` passes on to the
+    /// scraped site, one name per line, lowercased. Empty forwards nothing.
+    pub get_forward_headers: Vec<String>,
+    /// `config_playlist_merge_concurrency`: sources `/playlist/all` scrapes
+    /// at once, on top of each source's own page concurrency
+    pub playlist_merge_concurrency: usize,
+}
+
+impl AppConfig {
+    pub async fn load(env: &worker::Env) -> Result<Self> {
+        let kv = env
+            .kv("VID_PLAYLIST_MANAGER_KV")
+            .map_err(|e| anyhow!("Failed to open kv: {e:?}"))?;
+
+        let playlist = load_playlist(env, &kv).await.map_err(|e| {
+            tracing::warn!("Invalid `config_playlist`: {e}");
+            e.to_string()
+        });
+
+        let discord_channels = env
+            .secret("DISCORD_CHANNEL_IDS")
+            .map(|x| {
+                x.to_string()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            playlist,
+            discord: DiscordConfig::from_kv(&kv).await?,
+            discord_channels,
+            discord_request_budget: env_usize(env, "DISCORD_SUBREQUEST_BUDGET")
+                .unwrap_or(crate::discord::DEFAULT_REQUEST_BUDGET),
+            discord_channel_concurrency: env_usize(env, "DISCORD_CHANNEL_CONCURRENCY")
+                .unwrap_or(crate::discord::DEFAULT_CHANNEL_CONCURRENCY),
+            kv_bulk_key_limit: env_usize(env, "KV_BULK_KEY_LIMIT")
+                .unwrap_or(crate::kvmanager::DEFAULT_BULK_KEY_LIMIT),
+            url_guard: UrlGuard::from_kv(&kv).await?,
+            text_template: get_parsed(&kv, "config_template_text").await?,
+            get_forward_headers: get_parsed::<String>(&kv, "config_get_forward_headers")
+                .await?
+                .unwrap_or_default()
+                .lines()
+                .map(|x| x.trim().to_ascii_lowercase())
+                // The worker sets Host from the URL, it can't be forwarded
+                .filter(|x| !x.is_empty() && x != "host")
+                .collect(),
+            playlist_merge_concurrency: get_parsed(&kv, "config_playlist_merge_concurrency")
+                .await?
+                .filter(|x| *x > 0)
+                .unwrap_or(crate::playlistviewer::DEFAULT_MERGE_CONCURRENCY),
+        })
+    }
+
+    /// The playlist config, `None` when there's none yet, failing when it's invalid
+    pub fn playlist_opt(&self) -> Result<Option<&PlaylistConfig>> {
+        match &self.playlist {
+            Ok(c) => Ok(c.as_ref()),
+            Err(e) => Err(anyhow!("Invalid `config_playlist`: {e}")),
+        }
+    }
+
+    /// The playlist config, failing when there's none yet or it's invalid
+    pub fn playlist(&self) -> Result<&PlaylistConfig> {
+        match &self.playlist {
+            Ok(Some(c)) => Ok(c),
+            Ok(None) => Err(anyhow!(
+                "`config_playlist` isn't set, add it through /kv/new"
+            )),
+            Err(e) => Err(anyhow!("Invalid `config_playlist`: {e}")),
+        }
+    }
+}
+
+async fn load_playlist(env: &worker::Env, kv: &KvStore) -> Result<Option<PlaylistConfig>> {
+    match PlaylistConfig::from_kv_opt(kv).await? {
+        Some(c) => {
+            c.validate()?;
+            Ok(Some(c.resolve_secrets(env)?))
+        }
+        None => Ok(None),
+    }
+}
@@ -1,23 +1,450 @@
-use std::sync::LazyLock;
+use std::collections::HashMap;
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
 
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 use anyhow::Result;
+use backon::{ExponentialBuilder, Retryable};
 use time::UtcDateTime;
 
+use crate::links::LinkRecord;
+
 const DISCORD_API: &str = "https://discord.com/api/v10";
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
+/// Cloudflare caps a worker invocation at 1000 subrequests. Keep some headroom
+/// for the KV calls around the Discord ones.
+pub const DEFAULT_REQUEST_BUDGET: usize = 900;
+/// Channels the cron job fetches at once, unless `DISCORD_CHANNEL_CONCURRENCY` says otherwise
+pub const DEFAULT_CHANNEL_CONCURRENCY: usize = 8;
+/// A channel fetch needs at least the channel, guild and messages calls
+const CHANNEL_REQUEST_RESERVE: usize = 3;
+/// The API answers within a second or two, longer means it's struggling
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+/// Safety limit on paging back through a channel, in case of a runout
+const RANGE_FETCH_TIMEOUT: web_time::Duration = web_time::Duration::from_secs(60 * 5);
+
+/// How a token is put in the `Authorization` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Bot tokens need a `Bot ` prefix
+    Bot,
+    /// User tokens are sent bare
+    User,
+}
+
+impl std::str::FromStr for TokenKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "bot" => Ok(Self::Bot),
+            "user" => Ok(Self::User),
+            other => Err(anyhow::anyhow!("Unknown token kind `{other}`")),
+        }
+    }
+}
+
+impl TokenKind {
+    fn header_value(&self, token: &str) -> String {
+        let bare = token.strip_prefix("Bot ").unwrap_or(token);
+        match self {
+            Self::Bot => format!("Bot {bare}"),
+            Self::User => bare.to_string(),
+        }
+    }
+}
+
+/// Knobs of the link collection, each read from its own `config_*` KV key
+#[derive(Clone, Debug)]
+pub struct DiscordConfig {
+    /// `config_link_allows` and `config_exclude_mode`
+    pub filter: crate::links::LinkFilter,
+    /// `config_link_rewrites`: host or regex rewrites applied to collected
+    /// links, see [`crate::links::LinkRewrites`]. Empty rewrites nothing.
+    pub rewrites: crate::links::LinkRewrites,
+    /// `config_link_metadata`: store JSON lines with author/channel/time instead of bare URLs
+    pub link_metadata: bool,
+    /// `config_min_links`: runs collecting fewer links are buffered until the next one
+    pub min_links: usize,
+    /// `config_one_link_per_message`: only keep the first link of each message
+    pub one_link_per_message: bool,
+    /// `config_schemeless_links`: also pick up bare domains like `example.com/video`
+    pub schemeless_links: bool,
+    /// `config_merge_mode`: how each run's links are written to the merged key
+    pub merge_mode: MergeMode,
+    /// `config_merge_order`: how a run's links are ordered before being written
+    pub merge_order: MergeOrder,
+    /// `config_seen_retention_days`: skip links already captured within that many
+    /// days, in any month. Unset disables the check and its extra KV read.
+    pub seen_retention_days: Option<u32>,
+    /// `config_max_age_days`: drop links from messages older than that many days,
+    /// even inside the fetched range. Unset means no limit.
+    pub max_age_days: Option<u32>,
+    /// `config_min_content_len`: skip messages shorter than that many
+    /// characters, like one-word spam carrying a junk link. 0 keeps them all.
+    pub min_content_len: usize,
+    /// `config_max_links_per_run`: keep only the most recent that many links of
+    /// a run, against a flood in one channel. Unset means no limit.
+    pub max_links_per_run: Option<usize>,
+    /// `config_merged_ttl_days`: let merged keys expire after that many days.
+    /// Unset keeps them forever.
+    pub merged_ttl_days: Option<u32>,
+    /// `config_merged_ttl_mode`: what the TTL above is counted from
+    pub merged_ttl_mode: TtlMode,
+    /// `config_message_limit`: most messages fetched per channel and run, so
+    /// a busy channel can't use up the run's time and subrequests. Unset means
+    /// no limit.
+    pub message_limit: Option<usize>,
+    /// `config_dedup_mode`: which repeats of a link the merged key keeps,
+    /// against what it holds already and within the run. `global` by default.
+    pub dedup_mode: crate::links::DedupMode,
+    /// `config_dedup_ignore_scheme`: count `http://` and `https://` of the
+    /// same link as a repeat. Off by default, as not every site serves both.
+    pub dedup_ignore_scheme: bool,
+    /// `config_kv_retries`: extra attempts for the merged key's read and write
+    /// before the run gives up
+    pub kv_retries: usize,
+    /// `config_cron_active_hours`: UTC hours the cron job runs in, outside
+    /// them it returns right away. Unset runs around the clock.
+    pub active_hours: Option<ActiveHours>,
+    /// `config_discord_channels`: per-channel settings keyed by channel id.
+    /// Channels missing from it use the defaults, so without it all are enabled.
+    pub channels: HashMap<String, ChannelSettings>,
+}
+
+/// One channel's entry in `config_discord_channels`, a TOML table like
+///
+/// ```toml
+/// [123456789012345678]
+/// enabled = false
+/// name = "memes"
+/// message_limit = 500
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChannelSettings {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Stored as the link's channel instead of the name Discord reports
+    pub name: Option<String>,
+    /// Overrides `config_message_limit` for this channel
+    pub message_limit: Option<usize>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl DiscordConfig {
+    pub async fn from_kv(kv: &worker::KvStore) -> Result<Self> {
+        use crate::config::get_parsed;
+
+        Ok(Self {
+            filter: crate::links::LinkFilter::from_kv(kv).await?,
+            rewrites: get_parsed(kv, "config_link_rewrites")
+                .await?
+                .unwrap_or_default(),
+            link_metadata: get_parsed(kv, "config_link_metadata")
+                .await?
+                .unwrap_or(false),
+            min_links: get_parsed(kv, "config_min_links").await?.unwrap_or(1),
+            one_link_per_message: get_parsed(kv, "config_one_link_per_message")
+                .await?
+                .unwrap_or(false),
+            schemeless_links: get_parsed(kv, "config_schemeless_links")
+                .await?
+                .unwrap_or(false),
+            merge_mode: get_parsed(kv, "config_merge_mode")
+                .await?
+                .unwrap_or_default(),
+            merge_order: get_parsed(kv, "config_merge_order")
+                .await?
+                .unwrap_or_default(),
+            seen_retention_days: get_parsed(kv, "config_seen_retention_days").await?,
+            max_age_days: get_parsed(kv, "config_max_age_days").await?,
+            min_content_len: get_parsed(kv, "config_min_content_len").await?.unwrap_or(0),
+            max_links_per_run: get_parsed(kv, "config_max_links_per_run").await?,
+            merged_ttl_days: get_parsed(kv, "config_merged_ttl_days").await?,
+            merged_ttl_mode: get_parsed(kv, "config_merged_ttl_mode")
+                .await?
+                .unwrap_or_default(),
+            message_limit: get_parsed(kv, "config_message_limit").await?,
+            dedup_mode: get_parsed(kv, "config_dedup_mode")
+                .await?
+                .unwrap_or(crate::links::DedupMode::Global),
+            dedup_ignore_scheme: get_parsed(kv, "config_dedup_ignore_scheme")
+                .await?
+                .unwrap_or(false),
+            kv_retries: get_parsed(kv, "config_kv_retries").await?.unwrap_or(3),
+            active_hours: get_parsed(kv, "config_cron_active_hours").await?,
+            channels: Self::channels_from_kv(kv).await?,
+        })
+    }
+
+    async fn channels_from_kv(kv: &worker::KvStore) -> Result<HashMap<String, ChannelSettings>> {
+        let tomlstr = kv
+            .get(&crate::kvprefix::key("config_discord_channels"))
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))?
+            .unwrap_or_default();
+
+        toml::from_str(&tomlstr)
+            .map_err(|e| anyhow::anyhow!("Invalid value for `config_discord_channels`: {e}"))
+    }
+
+    /// Seconds left before the merged key of `month` (`YYYY-MM`) should expire
+    fn merged_ttl(&self, month: &str) -> Result<Option<u64>> {
+        let Some(days) = self.merged_ttl_days else {
+            return Ok(None);
+        };
+        let ttl = u64::from(days) * 86_400;
+
+        match self.merged_ttl_mode {
+            TtlMode::Sliding => Ok(Some(ttl)),
+            TtlMode::Fixed => {
+                let start = utils::parse_month(month)?
+                    .midnight()
+                    .as_utc()
+                    .unix_timestamp();
+                let elapsed = UtcDateTime::now().unix_timestamp() - start;
+
+                // KV refuses TTLs under a minute
+                Ok(Some((ttl as i64 - elapsed).max(60) as u64))
+            }
+        }
+    }
+
+    /// Message limit of a channel, its own or the global one
+    pub fn message_limit(&self, ch_id: &str) -> Option<usize> {
+        self.channels
+            .get(ch_id)
+            .and_then(|x| x.message_limit)
+            .or(self.message_limit)
+            .filter(|x| *x > 0)
+    }
+
+    /// A fresh [`crate::links::Deduper`] per `config_dedup_mode` and
+    /// `config_dedup_ignore_scheme`
+    pub fn deduper(&self) -> crate::links::Deduper {
+        crate::links::Deduper::new(self.dedup_mode).ignoring_scheme(self.dedup_ignore_scheme)
+    }
+
+    pub fn is_channel_enabled(&self, ch_id: &str) -> bool {
+        self.channels.get(ch_id).is_none_or(|x| x.enabled)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeMode {
+    /// Add the run's links after what the monthly key already has
+    #[default]
+    Append,
+    /// Overwrite the monthly key with only the run's links, deduplicated
+    /// as `config_dedup_mode` says.
+    /// Manual cleanups of the key then don't come back on the next run.
+    Replace,
+}
+
+impl std::str::FromStr for MergeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "append" => Ok(Self::Append),
+            "replace" => Ok(Self::Replace),
+            other => Err(anyhow::anyhow!("Unknown merge mode `{other}`")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeOrder {
+    /// Grouped by channel, in `DISCORD_CHANNEL_IDS` order
+    #[default]
+    Channel,
+    /// By source message time, across all channels
+    Chronological,
+}
+
+impl std::str::FromStr for MergeOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "channel" => Ok(Self::Channel),
+            "chronological" => Ok(Self::Chronological),
+            other => Err(anyhow::anyhow!("Unknown merge order `{other}`")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TtlMode {
+    /// Counted from the last write. Every append resets it, so a key only
+    /// expires once its month has been quiet for the whole TTL.
+    #[default]
+    Sliding,
+    /// Counted from the start of the key's month, whatever writes come later
+    Fixed,
+}
+
+impl std::str::FromStr for TtlMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "sliding" => Ok(Self::Sliding),
+            "fixed" => Ok(Self::Fixed),
+            other => Err(anyhow::anyhow!("Unknown TTL mode `{other}`")),
+        }
+    }
+}
+
+/// UTC hours the cron job runs in, `start-end` with both ends included, so
+/// `8-22` runs from 08:00 to 22:59. A range like `22-6` wraps past midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ActiveHours {
+    start: u8,
+    end: u8,
+}
+
+impl ActiveHours {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start <= self.end {
+            (self.start..=self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour <= self.end
+        }
+    }
+
+    /// Start of the latest active hour up to `t`. The last run happened
+    /// within it, so fetching from there covers the quiet hours after it.
+    fn last_active_start(&self, t: UtcDateTime) -> UtcDateTime {
+        let mut hour_start =
+            t.replace_time(time::Time::from_hms(t.hour(), 0, 0).expect("Hour is in range"));
+
+        for _ in 0..24 {
+            if self.contains(hour_start.hour()) {
+                break;
+            }
+            hour_start -= time::Duration::hours(1);
+        }
+
+        hour_start
+    }
+}
+
+impl std::str::FromStr for ActiveHours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("Active hours `{s}` aren't `start-end`"))?;
+        let hour = |x: &str| -> Result<u8> {
+            match x.trim().parse::<u8>() {
+                Ok(h) if h < 24 => Ok(h),
+                _ => Err(anyhow::anyhow!(
+                    "Hour `{}` isn't between 0 and 23",
+                    x.trim()
+                )),
+            }
+        };
+
+        Ok(Self {
+            start: hour(start)?,
+            end: hour(end)?,
+        })
+    }
+}
+
+/// Error body Discord sends along with non-2xx responses
+#[derive(Deserialize, Debug)]
+pub struct DiscordApiError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl DiscordApiError {
+    /// Reword a failed request with Discord's own error, e.g. "Discord 10003 Unknown Channel"
+    fn describe(err: anyhow::Error) -> anyhow::Error {
+        let Some(http) = err.downcast_ref::<crate::fetcher::HttpError>() else {
+            return err;
+        };
+
+        let Ok(api) = serde_json::from_str::<Self>(http.body()) else {
+            return err;
+        };
+
+        let msg = format!(
+            "Discord {} {} (HTTP {})",
+            api.code,
+            api.message,
+            http.status()
+        );
+        err.context(msg)
+    }
+}
+
 #[derive(Clone)]
 pub struct DiscordClient {
     fetcher: crate::fetcher::Client,
     kv: crate::kvcache::KvCache,
+
+    /// Subrequests the invocation sent so far, retries included, see
+    /// `subrequests::Subrequests`. The budget is against Cloudflare's limit,
+    /// which counts all of them, not only the Discord ones.
+    requests: Arc<AtomicUsize>,
+    request_budget: usize,
 }
 
 #[allow(dead_code)]
 impl DiscordClient {
-    pub fn new(token: impl AsRef<str>, kv: worker::KvStore) -> Result<Self> {
+    /// Client from the worker env: `DISCORD_TOKEN`, formatted per the optional
+    /// `DISCORD_TOKEN_KIND` var (`bot`/`user`), and the `KVCACHE` namespace.
+    pub fn from_env(
+        env: &worker::Env,
+        subrequests: &crate::subrequests::Subrequests,
+    ) -> Result<Self> {
+        let token = env.secret("DISCORD_TOKEN")?.to_string();
+        let kv = env.kv("KVCACHE")?;
+
+        match env.var("DISCORD_TOKEN_KIND") {
+            Ok(kind) => Self::new_with_kind(token, kind.to_string().parse()?, kv, subrequests),
+            Err(_) => Self::new(token, kv, subrequests),
+        }
+    }
+
+    /// Client for a bot token, adding the `Bot ` prefix if it's missing
+    pub fn new_bot(
+        token: impl AsRef<str>,
+        kv: worker::KvStore,
+        subrequests: &crate::subrequests::Subrequests,
+    ) -> Result<Self> {
+        Self::new_with_kind(token, TokenKind::Bot, kv, subrequests)
+    }
+
+    pub fn new_with_kind(
+        token: impl AsRef<str>,
+        kind: TokenKind,
+        kv: worker::KvStore,
+        subrequests: &crate::subrequests::Subrequests,
+    ) -> Result<Self> {
+        Self::new(kind.header_value(token.as_ref()), kv, subrequests)
+    }
+
+    /// Client using `token` verbatim as the `Authorization` header
+    pub fn new(
+        token: impl AsRef<str>,
+        kv: worker::KvStore,
+        subrequests: &crate::subrequests::Subrequests,
+    ) -> Result<Self> {
         let mut headers = http::HeaderMap::new();
         headers.append(
             "User-Agent",
@@ -29,17 +456,49 @@ impl DiscordClient {
         );
 
         Ok(Self {
-            fetcher: crate::fetcher::Client::new(DISCORD_API).with_headers(headers),
+            fetcher: crate::fetcher::Client::new(DISCORD_API)
+                .with_headers(headers)
+                .with_subrequests(subrequests)
+                .with_connect_timeout(CONNECT_TIMEOUT)
+                .with_read_timeout(READ_TIMEOUT),
             kv: crate::kvcache::KvCache::new(kv),
+
+            requests: subrequests.counter(),
+            request_budget: DEFAULT_REQUEST_BUDGET,
         })
     }
 
+    pub fn with_request_budget(self, budget: usize) -> Self {
+        Self {
+            request_budget: budget,
+            ..self
+        }
+    }
+
+    /// Number of subrequests sent in this invocation, counting every retry
+    /// as the subrequest it is
+    pub fn request_count(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn budget_remaining(&self) -> usize {
+        self.request_budget.saturating_sub(self.request_count())
+    }
+
+    /// Whether there's not enough budget left to start fetching another channel
+    pub fn is_budget_near_exhausted(&self) -> bool {
+        self.budget_remaining() < CHANNEL_REQUEST_RESERVE
+    }
+
     /// Internal helper to send authorized GET requests and parse JSON
     async fn get_json<T>(&self, endpoint: &str) -> Result<T>
     where
         T: serde::de::DeserializeOwned,
     {
-        self.fetcher.get_json(endpoint).await
+        self.fetcher
+            .get_json(endpoint)
+            .await
+            .map_err(DiscordApiError::describe)
     }
 
     /// Internal helper to send authorized GET requests and parse JSON
@@ -75,6 +534,11 @@ impl DiscordClient {
             .await
     }
 
+    /// The account the token belongs to. Cheap, so good for checking the token.
+    pub async fn get_current_user(&self) -> Result<User> {
+        self.get_json::<User>("/users/@me").await
+    }
+
     /// Get the last N messages
     pub async fn get_messages(&self, channel_id: &str, limit: u8) -> Result<Vec<Message>> {
         if limit == 0 {
@@ -109,29 +573,24 @@ impl DiscordClient {
         date_range: impl std::ops::RangeBounds<time::UtcDateTime>,
         limit: Option<usize>,
     ) -> Result<Vec<Message>> {
+        self.fetch_messages_range(channel_id, date_range, limit)
+            .await
+            .map(|x| x.messages)
+    }
+
+    /// [`Self::get_messages_range`], also telling whether the time limit or
+    /// request budget cut it short while older messages in range may remain
+    pub async fn fetch_messages_range(
+        &self,
+        channel_id: &str,
+        date_range: impl std::ops::RangeBounds<time::UtcDateTime>,
+        limit: Option<usize>,
+    ) -> Result<MessageRange> {
         let mut messages = Vec::<Message>::new();
         // let range = date_range.start_bound()
         // let before_id = utils::unix_ms_to_snowflake(timestamp_ms, worker_id, sequence)
 
-        let filter_msg = |msgs: Vec<Message>| {
-            // Limit messages to containing date_range
-            // Messages sorted by newest, descending
-            // Find middlepoint to split if any
-            let split_idx = msgs.partition_point(|x| {
-                if let Ok(t) = x.timestamp() {
-                    date_range.contains(&t)
-                } else {
-                    false
-                }
-            });
-
-            // If split_idx is anywhere below 100
-            if split_idx < 100 {
-                return msgs[..split_idx].to_vec();
-            }
-
-            msgs
-        };
+        let filter_msg = |msgs: Vec<Message>| keep_in_range(msgs, &date_range);
 
         // First round of message batch
         messages.append(&mut filter_msg(match date_range.end_bound() {
@@ -150,20 +609,16 @@ impl DiscordClient {
             }
         }));
 
-        if messages.is_empty() {
-            return Ok(vec![]);
-        }
-
         if messages.len() < 100 {
-            return Ok(messages);
+            return Ok(MessageRange {
+                messages,
+                truncated: false,
+            });
         }
 
         tracing::info!("Msg more than 100. Fetching more...");
 
-        // Safety measure in case of a runouts
-        // Limit fetch loop to 5 min
         let timeout_now = web_time::Instant::now();
-        let timeout_dur = web_time::Duration::from_secs(60 * 5);
 
         //The loop continues while all these are true:
         //  there’s no limit or if we’re under the limit.
@@ -171,11 +626,12 @@ impl DiscordClient {
         //  Its timestamp is valid,
         //  That timestamp is inside the date_range.
         //  Also within safety margin
-        while limit.is_none_or(|limit| messages.len() <= limit)
+        while limit.is_none_or(|limit| messages.len() < limit)
             && let Some(lastmsg) = messages.last()
             && let Ok(x) = lastmsg.timestamp()
             && date_range.contains(&x)
-            && timeout_now.elapsed() < timeout_dur
+            && timeout_now.elapsed() < RANGE_FETCH_TIMEOUT
+            && self.budget_remaining() > 0
         {
             let cap = if let Some(l) = limit {
                 (l - messages.len()).min(100)
@@ -195,15 +651,114 @@ impl DiscordClient {
             messages.append(&mut newmsg);
         }
 
-        Ok(messages)
+        if self.budget_remaining() == 0 {
+            tracing::warn!("Request budget exhausted, messages of {channel_id} may be truncated");
+        }
+
+        let out_of_time = timeout_now.elapsed() >= RANGE_FETCH_TIMEOUT;
+        let truncated = (out_of_time || self.budget_remaining() == 0)
+            && limit.is_none_or(|limit| messages.len() < limit)
+            && messages
+                .last()
+                .and_then(|x| x.timestamp().ok())
+                .is_some_and(|x| date_range.contains(&x));
+
+        Ok(MessageRange {
+            messages,
+            truncated,
+        })
     }
+
+    /// Messages of a channel between `from` and `to`, newest first, yielded a
+    /// batch per request as they come in instead of all at the end.
+    ///
+    /// Stops on the same time limit and request budget as [`Self::get_messages_range`].
+    pub fn stream_messages_range(
+        &self,
+        channel_id: &str,
+        from: UtcDateTime,
+        to: UtcDateTime,
+    ) -> impl futures::Stream<Item = Result<Vec<Message>>> + 'static {
+        struct State {
+            client: DiscordClient,
+            channel_id: String,
+            /// Snowflake the next batch is fetched before, `None` for the first
+            before: Option<String>,
+            done: bool,
+            started: web_time::Instant,
+        }
+
+        let state = State {
+            client: self.clone(),
+            channel_id: channel_id.to_string(),
+            before: None,
+            done: false,
+            started: web_time::Instant::now(),
+        };
+
+        futures::stream::try_unfold(state, move |mut state| async move {
+            if state.done {
+                return Ok(None);
+            }
+            if state.started.elapsed() >= RANGE_FETCH_TIMEOUT
+                || state.client.budget_remaining() == 0
+            {
+                tracing::warn!(
+                    "Out of time or request budget, messages of {} are truncated",
+                    state.channel_id
+                );
+                return Ok(None);
+            }
+
+            let before = match state.before.take() {
+                Some(before) => before,
+                None => utils::unix_ms_to_snowflake(to.unix_timestamp() * 1000, 0, 0)?,
+            };
+            let batch = state
+                .client
+                .get_messages_before(&state.channel_id, &before, 100)
+                .await?;
+            let fetched = batch.len();
+
+            state.before = batch.last().map(|x| x.id.clone());
+            let kept = batch
+                .into_iter()
+                .filter(|x| match x.timestamp() {
+                    Ok(t) => t >= from,
+                    // Kept, like `get_messages_range` does
+                    Err(_) => true,
+                })
+                .collect_vec();
+
+            // Newest first, so a message older than `from` means the range is done
+            state.done = fetched < 100 || kept.len() < fetched;
+
+            if fetched == 0 {
+                return Ok(None);
+            }
+
+            Ok(Some((kept, state)))
+        })
+    }
+}
+
+/// Messages of [`DiscordClient::fetch_messages_range`], newest first
+pub struct MessageRange {
+    pub messages: Vec<Message>,
+    /// Stopped by the time limit or request budget before reaching the start
+    /// of the range. Fetching before the last message picks it up again.
+    pub truncated: bool,
 }
 
 #[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Channel {
     pub id: String,
-    pub name: String,
+    /// `None` for DMs, named after their recipients instead
+    #[serde(default)]
+    pub name: Option<String>,
+    /// `None` for DMs and group DMs
+    #[serde(default)]
     pub guild_id: Option<String>,
 }
 
@@ -236,8 +791,35 @@ impl Message {
     }
 }
 
+/// Cut a batch of messages at the first one older than `date_range`
+fn keep_in_range(
+    msgs: Vec<Message>,
+    date_range: &impl std::ops::RangeBounds<time::UtcDateTime>,
+) -> Vec<Message> {
+    // Limit messages to containing date_range
+    // Messages sorted by newest, descending
+    // Find middlepoint to split if any
+    let split_idx = msgs.partition_point(|x| match x.timestamp() {
+        Ok(t) => date_range.contains(&t),
+        // Snowflakes are well-formed in practice, so this is more likely
+        // a parsing bug than a message out of range. Cutting the batch
+        // here would drop every older message with it.
+        Err(e) => {
+            tracing::warn!("Keeping message {} with unreadable timestamp: {e}", x.id);
+            true
+        }
+    });
+
+    // If split_idx is anywhere below 100
+    if split_idx < 100 {
+        return msgs[..split_idx].to_vec();
+    }
+
+    msgs
+}
+
 #[allow(dead_code)]
-mod utils {
+pub mod utils {
     use anyhow::*;
     use time::UtcDateTime;
     use time::{Date, Month};
@@ -309,17 +891,58 @@ mod utils {
     }
 }
 
-pub async fn mainfn(env: &worker::Env, sched_diff: i64) -> Result<()> {
-    let token = env.secret("DISCORD_TOKEN")?;
-    let channels = env.secret("DISCORD_CHANNEL_IDS")?.to_string();
-    let channels = channels.split(",").collect::<Vec<_>>();
+pub async fn mainfn(
+    env: &worker::Env,
+    appconfig: &crate::config::AppConfig,
+    subrequests: &crate::subrequests::Subrequests,
+    sched_diff: i64,
+) -> Result<()> {
+    anyhow::ensure!(
+        !appconfig.discord_channels.is_empty(),
+        "DISCORD_CHANNEL_IDS isn't set"
+    );
+
+    let config = &appconfig.discord;
+    let currtime = time::UtcDateTime::now();
+
+    if let Some(hours) = config.active_hours
+        && !hours.contains(currtime.hour())
+    {
+        tracing::info!(
+            "{:02}:00 UTC is outside config_cron_active_hours, skipping this run",
+            currtime.hour()
+        );
+        return Ok(());
+    }
 
     let kv = env.kv("VID_PLAYLIST_MANAGER_KV")?;
 
-    let client = DiscordClient::new(token.to_string(), env.kv("KVCACHE")?)?;
+    let client = DiscordClient::from_env(env, subrequests)?
+        .with_request_budget(appconfig.discord_request_budget);
+
+    let (channels, disabled): (Vec<_>, Vec<_>) = appconfig
+        .discord_channels
+        .iter()
+        .map(String::as_str)
+        .partition(|x| config.is_channel_enabled(x));
+    if !disabled.is_empty() {
+        tracing::info!(
+            "Skipping channels disabled in config_discord_channels: {}",
+            disabled.join(", ")
+        );
+    }
 
-    let currtime = time::UtcDateTime::now();
     let prevtime = currtime.saturating_sub(time::Duration::minutes(sched_diff));
+    // The runs skipped in quiet hours fetched nothing, so the first run after
+    // them reaches back to the last active hour
+    let start = match config.active_hours {
+        Some(hours) if !hours.contains(prevtime.hour()) => {
+            let t = hours.last_active_start(prevtime);
+            tracing::info!("First run after quiet hours, fetching from {t}");
+            t
+        }
+        _ => prevtime,
+    };
 
     {
         let timefmt = time::format_description::parse("[hour]:[minute]:[second]")?;
@@ -327,121 +950,626 @@ pub async fn mainfn(env: &worker::Env, sched_diff: i64) -> Result<()> {
         tracing::debug!("It is currently {timestr}");
     }
 
-    let range = prevtime..currtime;
+    let backfill = load_backfill(&kv).await?;
+    let since = match backfill {
+        Some(t) if t < start => {
+            tracing::info!("Backfilling from {t} instead of the last run");
+            t
+        }
+        _ => start,
+    };
+
+    let range = since..currtime;
     tracing::debug!("{range:?}");
 
-    let sem = std::sync::Arc::new(async_lock::Semaphore::new(8));
+    let sem = std::sync::Arc::new(async_lock::Semaphore::new(
+        appconfig.discord_channel_concurrency,
+    ));
 
     let urls_getter = futures::future::join_all(
         channels
             .iter()
-            .map(|x| (x, client.clone(), range.clone(), sem.clone()))
-            .map(|(x, c, r, sem)| async move {
+            .map(|x| (x, client.clone(), range.clone(), sem.clone(), config, &kv))
+            .map(|(x, c, r, sem, cfg, kv)| async move {
                 let _permit = sem.acquire().await;
-                ch_fetcher(&c, x, r).await
+
+                // Checked after acquiring, as other channels may've used the budget up meanwhile
+                if c.is_budget_near_exhausted() {
+                    return Ok(None);
+                }
+
+                let watermark = load_watermark(kv, x).await?;
+                let r = match watermark {
+                    Some(t) => {
+                        tracing::info!("Fetching {x} from its watermark at {t}");
+                        t..r.end
+                    }
+                    None => r,
+                };
+
+                let links = ch_fetcher(&c, x, r, cfg).await?;
+
+                Ok(Some((links, watermark.is_some())))
             }),
     )
     .await;
 
-    let (urls, errs): (Vec<Vec<String>>, Vec<anyhow::Error>) =
+    let skipped = channels
+        .iter()
+        .zip(&urls_getter)
+        .filter(|(_, res)| matches!(res, Ok(None)))
+        .map(|(ch, _)| *ch)
+        .collect_vec();
+
+    if !skipped.is_empty() {
+        tracing::warn!(
+            "Subrequest budget near exhausted ({} requests used), skipped channels: {}",
+            client.request_count(),
+            skipped.join(", ")
+        );
+    }
+
+    let watermarked = channels
+        .iter()
+        .zip(&urls_getter)
+        .filter(|(_, res)| matches!(res, Ok(Some((_, true)))))
+        .map(|(ch, _)| ch.to_string())
+        .collect_vec();
+
+    let (urls, errs): (Vec<Option<(Vec<LinkRecord>, bool)>>, Vec<anyhow::Error>) =
         urls_getter.into_iter().partition_result();
 
     errs.iter()
         .for_each(|err| tracing::error!(?err, "Fetch failed"));
 
-    let urls = urls.into_iter().flatten().collect_vec();
+    let mut records = urls
+        .into_iter()
+        .flatten()
+        .flat_map(|(links, _)| links)
+        .collect_vec();
+    if config.merge_order == MergeOrder::Chronological {
+        // RFC3339 in UTC, so comparing the strings compares the times.
+        // Stable, so links of the same message keep their order.
+        records.sort_by(|a, b| a.posted_at.cmp(&b.posted_at));
+    }
+
+    let mut commit = RunCommit {
+        // A channel failed or skipped didn't get the backfill, so the next
+        // run tries it again
+        backfill_done: backfill.is_some() && errs.is_empty() && skipped.is_empty(),
+        watermarks: watermarked,
+        ..Default::default()
+    };
+
+    let mut seen = match config.seen_retention_days {
+        Some(days) => {
+            let mut seen = load_seen(&kv).await?;
+            seen.prune(currtime.unix_timestamp() - i64::from(days) * 86_400);
+
+            let before = records.len();
+            records.retain(|x| !seen.contains(&x.url));
+            tracing::info!(
+                "Skipped {} links already seen in the last {days} days",
+                before - records.len()
+            );
+
+            Some(seen)
+        }
+        None => None,
+    };
+
+    if let Some(max) = config.max_links_per_run
+        && records.len() > max
+    {
+        tracing::warn!(
+            "Run capped by config_max_links_per_run: keeping the {max} most recent of {} links",
+            records.len()
+        );
+
+        // Newest first to pick them, then back to the run's order
+        let mut by_age = records.into_iter().enumerate().collect_vec();
+        by_age.sort_by(|(_, a), (_, b)| b.posted_at.cmp(&a.posted_at));
+        by_age.truncate(max);
+        by_age.sort_by_key(|(i, _)| *i);
+        records = by_age.into_iter().map(|(_, x)| x).collect_vec();
+    }
+
+    // Only the links kept are recorded, the ones capped off are still new to
+    // the next run
+    if let Some(seen) = &mut seen {
+        records.retain(|x| seen.insert(&x.url, currtime.unix_timestamp()));
+    }
+    commit.seen = seen;
+
+    // Plain URLs by default, JSON lines with author/channel/time when enabled
+    let urls = records
+        .into_iter()
+        .map(|x| {
+            if config.link_metadata {
+                serde_json::to_string(&x).expect("Failed to serialize link record")
+            } else {
+                x.url
+            }
+        })
+        .collect_vec();
+    crate::metrics::incr_by("cron_links_collected_total", urls.len() as u64);
+
+    let timefmt = time::format_description::parse("[year]-[month]")?;
+    let timestr = prevtime.format(&timefmt)?;
+
+    let min_links = config.min_links;
+    let mut pending = load_pending(&kv).await?;
+
+    // A buffer left from a previous month goes to that month's key, whatever its size
+    if let Some(p) = pending.take_if(|p| p.month != timestr) {
+        tracing::info!(
+            "Month rolled over, flushing {} pending links to {}",
+            p.links.len(),
+            p.month
+        );
+        write_merged(&kv, &p.month, &p.links, config).await?;
+        clear_pending(&kv).await?;
+    }
 
     if urls.is_empty() {
         let emfmt = time::format_description::parse("[hour]:[minute]:[second]")?;
         let emtime = prevtime.format(&emfmt)?;
         tracing::info!("No new links since {emtime}. Skipping sending to KV.");
 
-        return Ok(());
+        return commit.save(&kv).await;
     }
 
-    let timefmt = time::format_description::parse("[year]-[month]")?;
-    let timestr = prevtime.format(&timefmt)?;
+    let had_pending = pending.is_some();
+    let mut links = pending.map(|p| p.links).unwrap_or_default();
+    links.extend(urls);
 
-    let kvname = format!("{timestr}_discord_merged");
-    let kvvalue = &urls.join("\n");
+    if links.len() < min_links {
+        tracing::info!(
+            "{} links collected, below config_min_links ({min_links}). Buffering until next run.",
+            links.len()
+        );
+        save_pending(
+            &kv,
+            &PendingLinks {
+                month: timestr,
+                links,
+            },
+        )
+        .await?;
+
+        return commit.save(&kv).await;
+    }
 
-    {
-        tracing::debug!("Getting previous KV to append");
-        let prev = kv
-            .get(&kvname)
-            .text()
-            .await
-            .expect("Failed prepping KV get")
-            .unwrap_or("".into());
-        let newval = prev + "\n" + kvvalue.as_ref();
-
-        tracing::info!("Sending to KV");
-        kv.put(&kvname, &newval)
-            .expect("Failed prepping KV send")
-            .execute()
-            .await
-            .expect("Failed sending KV");
-        tracing::info!("Done!");
+    let collected = links.len();
+    let added = write_merged(&kv, &timestr, &links, config).await?;
+    if had_pending {
+        clear_pending(&kv).await?;
     }
+    commit.save(&kv).await?;
+
+    tracing::info!(
+        "Added {added} new links to {timestr}, skipped {} already stored",
+        collected - added
+    );
+    save_last_run(
+        &kv,
+        &LastRun {
+            finished_at: currtime.format(&time::format_description::well_known::Rfc3339)?,
+            month: timestr,
+            collected,
+            added,
+            duplicates_skipped: collected - added,
+        },
+    )
+    .await?;
 
     Ok(())
 }
 
-const EXCLUDED_PATTERNS: &[&str] = &[
-    "cdn.",
-    "tenor.",
-    "redgifs.",
-    "discordapp.",
-    "redd.it",
-    "media.tumblr.",
-];
-
-static FINDER: LazyLock<linkify::LinkFinder> = LazyLock::new(linkify::LinkFinder::new);
-static EXCLUDER: LazyLock<aho_corasick::AhoCorasick> = LazyLock::new(|| {
-    aho_corasick::AhoCorasick::builder()
-        .ascii_case_insensitive(true)
-        .build(EXCLUDED_PATTERNS)
-        .expect("Failed to init filter")
-});
-
-#[tracing::instrument(skip(client, range))]
+/// Summary of the last run that wrote to a merged key
+const LAST_RUN_KEY: &str = "cron_last_run";
+
+#[derive(Serialize, Debug)]
+struct LastRun {
+    /// RFC3339
+    finished_at: String,
+    month: String,
+    /// Links the run wrote, pending ones from earlier runs included
+    collected: usize,
+    /// Of those, the ones not already in the merged key
+    added: usize,
+    duplicates_skipped: usize,
+}
+
+async fn save_last_run(kv: &worker::KvStore, summary: &LastRun) -> Result<()> {
+    kv.put(&crate::kvprefix::key(LAST_RUN_KEY), summary)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to put kv: {e:?}"))
+}
+
+/// Links collected by runs that stayed below `config_min_links`
+const PENDING_KEY: &str = "discord_pending_links";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingLinks {
+    /// `YYYY-MM` the links belong to
+    month: String,
+    links: Vec<String>,
+}
+
+/// RFC3339 timestamp set by hand to make the next run start from it.
+/// Cleared by the first run writing its links with every channel fetched,
+/// or right away if it's malformed.
+const BACKFILL_KEY: &str = "cron_backfill_until";
+
+async fn load_backfill(kv: &worker::KvStore) -> Result<Option<UtcDateTime>> {
+    let value = kv
+        .get(&crate::kvprefix::key(BACKFILL_KEY))
+        .text()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))?;
+
+    let Some(value) = value else {
+        return Ok(None);
+    };
+
+    match time::OffsetDateTime::parse(value.trim(), &time::format_description::well_known::Rfc3339)
+    {
+        Ok(t) => Ok(Some(t.to_utc())),
+        Err(e) => {
+            tracing::warn!("Ignoring malformed {BACKFILL_KEY} `{value}`: {e}");
+            clear_backfill(kv).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn clear_backfill(kv: &worker::KvStore) -> Result<()> {
+    kv.delete(&crate::kvprefix::key(BACKFILL_KEY))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete kv: {e:?}"))
+}
+
+/// Message id set by hand through `/discord/:channel/watermark` to make that
+/// channel's next run start from it. One-shot: cleared once a run fetched the
+/// channel from it and its links were written or buffered.
+pub fn watermark_key(ch_id: &str) -> String {
+    format!("watermark_discord_{ch_id}")
+}
+
+async fn load_watermark(kv: &worker::KvStore, ch_id: &str) -> Result<Option<UtcDateTime>> {
+    let key = watermark_key(ch_id);
+    let value = kv
+        .get(&crate::kvprefix::key(&key))
+        .text()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))?;
+
+    let Some(value) = value else {
+        return Ok(None);
+    };
+
+    match utils::snowflake_to_utc_datetime(value.trim()) {
+        Ok(t) => Ok(Some(t)),
+        Err(e) => {
+            tracing::warn!("Ignoring malformed {key} `{value}`: {e}");
+            Ok(None)
+        }
+    }
+}
+
+async fn clear_watermark(kv: &worker::KvStore, ch_id: &str) -> Result<()> {
+    kv.delete(&crate::kvprefix::key(&watermark_key(ch_id)))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete kv: {e:?}"))
+}
+
+/// What a run records for the next ones, saved only once its links are
+/// written or buffered. A run failing before that leaves it all as it was,
+/// so the next run fetches the same links and tries again.
+#[derive(Default)]
+struct RunCommit {
+    /// Links seen so far, this run's included
+    seen: Option<SeenLinks>,
+    /// Whether the run reached back to [`BACKFILL_KEY`] for every channel,
+    /// so it's done with
+    backfill_done: bool,
+    /// Channels fetched from their watermark, see [`watermark_key`]
+    watermarks: Vec<String>,
+}
+
+impl RunCommit {
+    async fn save(self, kv: &worker::KvStore) -> Result<()> {
+        if let Some(seen) = &self.seen {
+            save_seen(kv, seen).await?;
+        }
+
+        if self.backfill_done {
+            clear_backfill(kv).await?;
+        }
+
+        for ch_id in &self.watermarks {
+            clear_watermark(kv, ch_id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Links captured by previous runs, see `config_seen_retention_days`
+const SEEN_KEY: &str = "discord_seen_links";
+/// Oldest entries are dropped past this, to keep the value well under the KV size limit
+const MAX_SEEN_LINKS: usize = 50_000;
+
+/// Link -> unix timestamp it was first captured
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SeenLinks(std::collections::HashMap<String, i64>);
+
+impl SeenLinks {
+    /// Forget links seen before `cutoff`, then the oldest ones past [`MAX_SEEN_LINKS`]
+    fn prune(&mut self, cutoff: i64) {
+        self.0.retain(|_, seen_at| *seen_at >= cutoff);
+
+        if self.0.len() > MAX_SEEN_LINKS {
+            let excess = self.0.len() - MAX_SEEN_LINKS;
+            let oldest = self
+                .0
+                .iter()
+                .sorted_by_key(|(_, seen_at)| **seen_at)
+                .take(excess)
+                .map(|(url, _)| url.clone())
+                .collect_vec();
+            oldest.iter().for_each(|x| {
+                self.0.remove(x);
+            });
+        }
+    }
+
+    fn contains(&self, url: &str) -> bool {
+        self.0.contains_key(url)
+    }
+
+    /// Record the link, returning whether it's new
+    fn insert(&mut self, url: &str, now: i64) -> bool {
+        if self.0.contains_key(url) {
+            return false;
+        }
+        self.0.insert(url.to_string(), now);
+        true
+    }
+}
+
+async fn load_seen(kv: &worker::KvStore) -> Result<SeenLinks> {
+    Ok(kv
+        .get(&crate::kvprefix::key(SEEN_KEY))
+        .json()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))?
+        .unwrap_or_default())
+}
+
+async fn save_seen(kv: &worker::KvStore, seen: &SeenLinks) -> Result<()> {
+    kv.put(&crate::kvprefix::key(SEEN_KEY), seen)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to put kv: {e:?}"))
+}
+
+fn merged_key(month: &str) -> String {
+    format!("{month}_discord_merged")
+}
+
+async fn load_pending(kv: &worker::KvStore) -> Result<Option<PendingLinks>> {
+    kv.get(&crate::kvprefix::key(PENDING_KEY))
+        .json()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))
+}
+
+async fn save_pending(kv: &worker::KvStore, pending: &PendingLinks) -> Result<()> {
+    kv.put(&crate::kvprefix::key(PENDING_KEY), pending)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to put kv: {e:?}"))
+}
+
+async fn clear_pending(kv: &worker::KvStore) -> Result<()> {
+    kv.delete(&crate::kvprefix::key(PENDING_KEY))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete kv: {e:?}"))
+}
+
+/// Write a run's links to the month's merged key, returning how many of them
+/// weren't stored already
+async fn write_merged(
+    kv: &worker::KvStore,
+    month: &str,
+    links: &[String],
+    config: &DiscordConfig,
+) -> Result<usize> {
+    let kvname = merged_key(month);
+    let ttl = config.merged_ttl(month)?;
+
+    let res = match config.merge_mode {
+        MergeMode::Append => {
+            append_merged(kv, &kvname, links, ttl, config.deduper(), config.kv_retries).await
+        }
+        MergeMode::Replace => {
+            tracing::info!("Replacing KV with this run's links");
+            let store = crate::kvcache::KvCache::new(kv.clone());
+            let mut dedup = config.deduper();
+            let unique = links
+                .iter()
+                .filter(|x| dedup.keep(&crate::links::line_url(x)))
+                .collect_vec();
+            let value = unique.iter().join("\n");
+            (|| store.set_compressed(&kvname, &value, ttl))
+                .retry(kv_backoff(config.kv_retries))
+                .notify(|err, dur| tracing::warn!("Retrying KV put after {dur:?}: {err}"))
+                .await
+                .map(|_| unique.len())
+        }
+    };
+
+    if let Err(e) = &res {
+        // Nothing else holds these links anymore, so leave them somewhere
+        // they can be copied back from
+        tracing::error!(
+            "Failed writing {} links to {kvname}: {e}\nUnwritten links:\n{}",
+            links.len(),
+            links.join("\n")
+        );
+    }
+
+    res
+}
+
+/// KV hiccups are usually gone within a second or two
+fn kv_backoff(retries: usize) -> ExponentialBuilder {
+    ExponentialBuilder::default()
+        .with_jitter()
+        .with_max_times(retries)
+        .with_min_delay(std::time::Duration::from_millis(500))
+}
+
+/// Append the links `dedup` keeps after what the merged key holds,
+/// read-modify-write. Returns how many were appended.
+async fn append_merged(
+    kv: &worker::KvStore,
+    kvname: &str,
+    links: &[String],
+    ttl: Option<u64>,
+    mut dedup: crate::links::Deduper,
+    retries: usize,
+) -> Result<usize> {
+    // Merged keys are gzipped so a busy month still fits in one KV value
+    let store = crate::kvcache::KvCache::new(kv.clone());
+
+    tracing::debug!("Getting previous KV to append");
+    let prev = (|| store.get_compressed(kvname))
+        .retry(kv_backoff(retries))
+        .notify(|err, dur| tracing::warn!("Retrying KV get after {dur:?}: {err}"))
+        .await?
+        .unwrap_or("".into());
+
+    // Compared by URL, so a link stored bare and again with metadata is one link
+    for line in prev.lines() {
+        dedup.keep(&crate::links::line_url(line));
+    }
+    let new_links = links
+        .iter()
+        .filter(|x| dedup.keep(&crate::links::line_url(x)))
+        .collect_vec();
+
+    if new_links.is_empty() {
+        tracing::info!("Every link is already stored, nothing to append");
+        return Ok(0);
+    }
+
+    let newval = prev + "\n" + new_links.iter().join("\n").as_str();
+
+    tracing::info!("Sending to KV");
+    (|| store.set_compressed(kvname, &newval, ttl))
+        .retry(kv_backoff(retries))
+        .notify(|err, dur| tracing::warn!("Retrying KV put after {dur:?}: {err}"))
+        .await?;
+    tracing::info!("Done!");
+
+    Ok(new_links.len())
+}
+
+#[tracing::instrument(skip(client, range, config), fields(elapsed_ms = tracing::field::Empty))]
 async fn ch_fetcher(
     client: &DiscordClient,
     ch_id: &str,
     range: impl std::ops::RangeBounds<UtcDateTime>,
-) -> Result<Vec<String>> {
-    let ch = client.get_channel(ch_id).await?;
-    let chname = ch.name;
-    let srv_id = ch
-        .guild_id
-        .expect("Failed to get Server ID (this shouldn't've been possible");
-    let srvname = client.get_guild(&srv_id).await?.name;
+    config: &DiscordConfig,
+) -> Result<Vec<LinkRecord>> {
+    let _timer = crate::timing::SpanTimer::start();
+    let ch = client
+        .get_channel(ch_id)
+        .await
+        .map_err(|e| e.context(format!("Failed looking up channel {ch_id}")))?;
+    let chname = channel_name(config.channels.get(ch_id), &ch);
+    // DMs have no server, their messages are fetched all the same
+    let srvname = match &ch.guild_id {
+        Some(srv_id) => {
+            client
+                .get_guild(srv_id)
+                .await
+                .map_err(|e| {
+                    e.context(format!(
+                        "Failed looking up server {srv_id} of channel {ch_id}"
+                    ))
+                })?
+                .name
+        }
+        None => "no server".to_string(),
+    };
     // let msg: Vec<Message> = client.get_messages(ch, 1).await?;
-    let msg_res = client.get_messages_range(ch_id, range, None).await?;
+    let limit = config.message_limit(ch_id);
+    let msg_res = client
+        .get_messages_range(ch_id, range, limit)
+        .await
+        .map_err(|e| e.context(format!("Failed fetching messages of channel {ch_id}")))?;
+    if let Some(limit) = limit
+        && msg_res.len() >= limit
+    {
+        tracing::warn!(
+            "Channel {chname} hit its message limit of {limit}, older messages in the range were skipped"
+        );
+    }
 
-    if let Some(m) = msg_res.first() {
+    if let Some(m) = msg_res.first()
+        && let Ok(t) = m.timestamp()
+    {
         let snip = m.content.clone();
-        let t_str = m
-            .timestamp()?
-            .format(&time::format_description::well_known::Rfc3339)?;
+        let t_str = t.format(&time::format_description::well_known::Rfc3339)?;
         tracing::debug!("First message snippet: [{t_str}] {snip}");
     }
 
     let msgcount = msg_res.len();
     tracing::trace!("msgcount: {msgcount}");
 
-    let links = msg_res
-        .into_iter()
-        .map(|x| x.content)
-        .flat_map(|x| {
-            FINDER
-                .links(&x)
-                .map(|x| x.as_str().to_string())
+    // Links of messages past `config_max_age_days` go, however wide the range
+    let msg_res = match config.max_age_days {
+        Some(days) => {
+            let cutoff = UtcDateTime::now() - time::Duration::days(days.into());
+            msg_res
+                .into_iter()
+                // Kept when unreadable, as in `keep_in_range`
+                .filter(|x| !x.timestamp().is_ok_and(|t| t < cutoff))
                 .collect_vec()
-        })
-        .collect_vec();
-
-    let filtered_count = links.iter().filter(|x| EXCLUDER.is_match(x)).count();
+        }
+        None => msg_res,
+    };
+
+    let msg_res = if config.min_content_len > 0 {
+        let (kept, short): (Vec<_>, Vec<_>) = msg_res
+            .into_iter()
+            .partition(|x| x.content.trim().chars().count() >= config.min_content_len);
+        if !short.is_empty() {
+            tracing::debug!(
+                "Skipped {} messages of {chname} shorter than {} characters",
+                short.len(),
+                config.min_content_len
+            );
+        }
+        kept
+    } else {
+        msg_res
+    };
+
+    let (mut links, filtered_count) = extract_message_links(&msg_res, &chname, config);
+    if !config.rewrites.is_empty() {
+        for link in &mut links {
+            link.url = config.rewrites.apply(&link.url);
+        }
+    }
 
     tracing::info!(
         "Fetched from {chname} ({srvname}): {} new message, {} new links, {} links excluded",
@@ -450,10 +1578,10 @@ async fn ch_fetcher(
         } else {
             &msgcount.to_string()
         },
-        if links.is_empty() {
+        if links.is_empty() && filtered_count == 0 {
             "no"
         } else {
-            &links.len().to_string()
+            &(links.len() + filtered_count).to_string()
         },
         if filtered_count == 0 {
             "no"
@@ -462,8 +1590,134 @@ async fn ch_fetcher(
         }
     );
 
-    Ok(links
-        .into_iter()
-        .filter(|x| !EXCLUDER.is_match(x))
-        .collect_vec())
+    Ok(links)
+}
+
+/// Name a channel's links are stored under: the one set in
+/// `config_discord_channels`, else Discord's, else the id of an unnamed DM
+fn channel_name(settings: Option<&ChannelSettings>, ch: &Channel) -> String {
+    settings
+        .and_then(|x| x.name.clone())
+        .or_else(|| ch.name.clone())
+        .unwrap_or_else(|| ch.id.clone())
+}
+
+/// Extract links from the messages' content, running them through the config's filter.
+///
+/// Returns the surviving links and how many were excluded.
+pub fn extract_message_links(
+    msgs: &[Message],
+    channel: &str,
+    config: &DiscordConfig,
+) -> (Vec<LinkRecord>, usize) {
+    let (excluded, kept): (Vec<_>, Vec<_>) = msgs
+        .iter()
+        .flat_map(|msg| {
+            let posted_at = msg
+                .timestamp()
+                .ok()
+                .and_then(|t| {
+                    t.format(&time::format_description::well_known::Rfc3339)
+                        .ok()
+                })
+                .unwrap_or_default();
+
+            // Only the primary link when a message pastes a bunch of them
+            let links = crate::links::extract_links(&msg.content, config.schemeless_links);
+            let take = if config.one_link_per_message {
+                1
+            } else {
+                links.len()
+            };
+
+            links.into_iter().take(take).map(move |url| LinkRecord {
+                url,
+                author: msg.author.username.clone(),
+                channel: channel.to_string(),
+                posted_at: posted_at.clone(),
+            })
+        })
+        .partition(|x| config.filter.is_excluded(&x.url));
+
+    (kept, excluded.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "content": "",
+            "author": { "id": "1", "username": "someone" },
+        }))
+        .unwrap()
+    }
+
+    fn snowflake_at(t: UtcDateTime) -> String {
+        utils::unix_ms_to_snowflake(t.unix_timestamp() * 1000, 0, 0).unwrap()
+    }
+
+    fn channel(json: serde_json::Value) -> Channel {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn dm_has_no_server_and_is_named_by_id() {
+        let ch = channel(serde_json::json!({
+            "id": "111",
+            "type": 1,
+            "recipients": [{ "id": "1", "username": "someone" }],
+        }));
+
+        assert_eq!(ch.guild_id, None);
+        assert_eq!(channel_name(None, &ch), "111");
+    }
+
+    #[test]
+    fn group_dm_keeps_its_name() {
+        let ch = channel(serde_json::json!({
+            "id": "222",
+            "type": 3,
+            "name": "friends",
+        }));
+
+        assert_eq!(ch.guild_id, None);
+        assert_eq!(channel_name(None, &ch), "friends");
+    }
+
+    #[test]
+    fn configured_name_beats_discords() {
+        let ch = channel(serde_json::json!({
+            "id": "333",
+            "name": "general",
+            "guild_id": "444",
+        }));
+        let settings: ChannelSettings = toml::from_str("name = \"memes\"").unwrap();
+
+        assert_eq!(channel_name(Some(&settings), &ch), "memes");
+    }
+
+    #[test]
+    fn unreadable_message_id_is_kept() {
+        // 2025-06-01 12:00 UTC
+        let end = UtcDateTime::from_unix_timestamp(1_748_779_200).unwrap();
+        let range = end - time::Duration::hours(1)..end;
+
+        let msgs = vec![
+            message(&snowflake_at(end - time::Duration::minutes(10))),
+            message("not-a-snowflake"),
+            message(&snowflake_at(end - time::Duration::hours(2))),
+        ];
+
+        let kept = keep_in_range(msgs, &range);
+        assert_eq!(
+            kept.iter().map(|x| x.id.as_str()).collect_vec(),
+            vec![
+                snowflake_at(end - time::Duration::minutes(10)).as_str(),
+                "not-a-snowflake"
+            ]
+        );
+    }
 }
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+
+use futures::{StreamExt, TryStreamExt};
+use itertools::Itertools;
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::AppData;
+use crate::discord::{DiscordClient, utils};
+
+fn discord_client(ctx: &RouteContext<AppData>) -> Result<DiscordClient> {
+    DiscordClient::from_env(&ctx.env, &ctx.data.subrequests)
+        .map_err(|e| worker::Error::RustError(format!("Failed to init Discord client: {e}")))
+}
+
+/// Read-only peek at the links the latest messages of a channel would yield.
+/// Nothing is written to the merged KV.
+pub async fn discord_recent(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let ch_id = if let Some(n) = ctx.param("channel") {
+        n
+    } else {
+        return Response::error("Channel not found", 404);
+    };
+
+    let limit = match req.url()?.query_pairs().find(|(k, _)| k == "limit") {
+        Some((_, v)) => match v.parse::<u8>() {
+            Ok(l) if (1..=100).contains(&l) => l,
+            _ => return Response::error("`limit` must be between 1 and 100", 400),
+        },
+        None => 50,
+    };
+
+    let client = discord_client(&ctx)?;
+    let config = match ctx.data.config(&ctx.env).await {
+        Ok(c) => &c.discord,
+        Err(e) => return Response::error(format!("Failed loading Discord config. {e}"), 500),
+    };
+
+    let msgs = match client.get_messages(ch_id, limit).await {
+        Ok(m) => m,
+        Err(e) => return Response::error(format!("Discord request failed. {e}"), 502),
+    };
+
+    let (links, excluded) = crate::discord::extract_message_links(&msgs, ch_id, config);
+    let links = links.into_iter().map(|x| x.url).collect::<Vec<_>>();
+
+    Response::from_json(&serde_json::json!({
+        "channel": ch_id,
+        "messages": msgs.len(),
+        "links": links,
+        "excluded": excluded,
+    }))
+}
+
+/// Tally the hosts of the links the latest messages of every enabled channel
+/// would yield, most common first. For tuning the link filter, nothing is
+/// written to the merged KV.
+pub async fn discord_hosts(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let limit = match req.url()?.query_pairs().find(|(k, _)| k == "limit") {
+        Some((_, v)) => match v.parse::<usize>() {
+            Ok(l) if l >= 1 => l,
+            _ => return Response::error("`limit` must be a positive number", 400),
+        },
+        None => 50,
+    };
+
+    let client = discord_client(&ctx)?;
+    let appconfig = match ctx.data.config(&ctx.env).await {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed loading Discord config. {e}"), 500),
+    };
+    let config = &appconfig.discord;
+
+    let channels = appconfig
+        .discord_channels
+        .iter()
+        .map(String::as_str)
+        .filter(|x| config.is_channel_enabled(x))
+        .collect::<Vec<_>>();
+
+    let fetched = futures::future::join_all(
+        channels
+            .iter()
+            .map(|ch| async { (*ch, client.get_messages(ch, 100).await) }),
+    )
+    .await;
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (ch, msgs) in fetched {
+        let msgs = match msgs {
+            Ok(m) => m,
+            Err(e) => return Response::error(format!("Discord request failed for {ch}. {e}"), 502),
+        };
+
+        let (links, _) = crate::discord::extract_message_links(&msgs, ch, config);
+        for link in links {
+            if let Some(host) = worker::Url::parse(&link.url)
+                .ok()
+                .and_then(|x| x.host_str().map(str::to_ascii_lowercase))
+            {
+                *counts.entry(host).or_default() += 1;
+            }
+        }
+    }
+
+    let hosts = counts
+        .into_iter()
+        .sorted_by(|(ha, a), (hb, b)| b.cmp(a).then_with(|| ha.cmp(hb)))
+        .take(limit)
+        .map(|(host, count)| serde_json::json!({"host": host, "count": count}))
+        .collect_vec();
+
+    Response::from_json(&hosts)
+}
+
+/// Longest span `/discord/:channel/range` fetches at once
+const MAX_RANGE_DAYS: i64 = 366;
+
+/// The `?from=` and `?to=` RFC3339 timestamps of `url`, `from` not after `to`
+fn parse_range(
+    url: &worker::Url,
+) -> std::result::Result<(time::UtcDateTime, time::UtcDateTime), String> {
+    let param = |name: &str| -> std::result::Result<time::UtcDateTime, String> {
+        let (_, v) = url
+            .query_pairs()
+            .find(|(k, _)| k == name)
+            .ok_or_else(|| format!("Missing `{name}`"))?;
+        time::OffsetDateTime::parse(&v, &time::format_description::well_known::Rfc3339)
+            .map(|x| x.to_utc())
+            .map_err(|e| format!("`{name}` must be an RFC3339 timestamp: {e}"))
+    };
+    let (from, to) = (param("from")?, param("to")?);
+
+    if from > to {
+        return Err("`from` must not be after `to`".to_string());
+    }
+
+    Ok((from, to))
+}
+
+/// Header carrying the snowflake to pass as `?after=` when a range came back truncated
+const RESUME_HEADER: &str = "X-Resume-After";
+
+/// Read-only fetch of the links a channel had between `?from=` and `?to=`
+/// (RFC3339), e.g. to check a backfill before running it. Nothing is written
+/// to the merged KV. Plain text with `Accept: text/plain`, JSON otherwise.
+///
+/// A range too large to fetch in one go is cut short with the last message
+/// fetched in the `X-Resume-After` header. Requesting it again with that
+/// value as `?after=` carries on with the messages older than it.
+pub async fn discord_range(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let ch_id = if let Some(n) = ctx.param("channel") {
+        n
+    } else {
+        return Response::error("Channel not found", 404);
+    };
+
+    let url = req.url()?;
+    let (from, to) = match parse_range(&url) {
+        Ok(range) => range,
+        Err(e) => return Response::error(e, 400),
+    };
+
+    let after = match url.query_pairs().find(|(k, _)| k == "after") {
+        Some((_, v)) => match (v.parse::<u64>(), utils::snowflake_to_utc_datetime(&v)) {
+            (Ok(id), Ok(t)) => Some((id, t)),
+            _ => return Response::error("`after` must be a message snowflake", 400),
+        },
+        None => None,
+    };
+
+    if to - from > time::Duration::days(MAX_RANGE_DAYS) {
+        return Response::error(
+            format!("The range can't span more than {MAX_RANGE_DAYS} days"),
+            400,
+        );
+    }
+
+    let as_text = req
+        .headers()
+        .get("Accept")?
+        .unwrap_or("".into())
+        .contains("text/plain");
+
+    let client = discord_client(&ctx)?;
+    let config = match ctx.data.config(&ctx.env).await {
+        Ok(c) => &c.discord,
+        Err(e) => return Response::error(format!("Failed loading Discord config. {e}"), 500),
+    };
+
+    // Timestamps only go down to the second, so the end is set just past the
+    // one of `after` and the messages not older than it are dropped below
+    let to = match after {
+        Some((_, t)) => to.min(t + time::Duration::seconds(1)),
+        None => to,
+    };
+
+    let range = match client.fetch_messages_range(ch_id, from..=to, None).await {
+        Ok(m) => m,
+        Err(e) => return Response::error(format!("Discord request failed. {e}"), 502),
+    };
+    let msgs = match after {
+        Some((after, _)) => range
+            .messages
+            .into_iter()
+            .filter(|x| x.id.parse::<u64>().is_ok_and(|id| id < after))
+            .collect_vec(),
+        None => range.messages,
+    };
+    let resume_after = msgs
+        .last()
+        .filter(|_| range.truncated)
+        .map(|x| x.id.clone());
+
+    let (links, excluded) = crate::discord::extract_message_links(&msgs, ch_id, config);
+    let links = links.into_iter().map(|x| x.url).collect_vec();
+
+    let mut res = if as_text {
+        Response::ok(links.join("\n"))?
+    } else {
+        Response::from_json(&serde_json::json!({
+            "channel": ch_id,
+            "messages": msgs.len(),
+            "links": links,
+            "excluded": excluded,
+            "resume_after": resume_after,
+        }))?
+    };
+
+    if let Some(id) = &resume_after {
+        res.headers_mut().set(RESUME_HEADER, id)?;
+    }
+    Ok(res)
+}
+
+/// Stream the links of `?channel=`, or of every enabled channel, posted
+/// between `?from=` and `?to=` (RFC3339) as JSON lines of
+/// `{url, author, channel, posted_at}`, written out as messages come in.
+/// Bound by the Discord request budget and the paging time limit.
+pub async fn discord_export(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let url = req.url()?;
+    let (from, to) = match parse_range(&url) {
+        Ok(range) => range,
+        Err(e) => return Response::error(e, 400),
+    };
+
+    let appconfig = match ctx.data.config(&ctx.env).await {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed loading Discord config. {e}"), 500),
+    };
+    let client = discord_client(&ctx)?.with_request_budget(appconfig.discord_request_budget);
+    let config = appconfig.discord.clone();
+
+    let channels = match url.query_pairs().find(|(k, _)| k == "channel") {
+        Some((_, ch)) => vec![ch.into_owned()],
+        None => appconfig
+            .discord_channels
+            .iter()
+            .filter(|x| config.is_channel_enabled(x))
+            .cloned()
+            .collect_vec(),
+    };
+
+    let body = futures::stream::iter(channels)
+        .map(move |ch| {
+            let config = config.clone();
+            client
+                .stream_messages_range(&ch, from, to)
+                .map_ok(move |msgs| {
+                    let (links, _) = crate::discord::extract_message_links(&msgs, &ch, &config);
+                    links
+                        .iter()
+                        .filter_map(|x| serde_json::to_string(x).ok())
+                        .map(|x| x + "\n")
+                        .collect::<String>()
+                })
+        })
+        .flatten()
+        .map_err(|e| worker::Error::RustError(e.to_string()));
+
+    let mut res = Response::from_stream(body)?;
+    res.headers_mut()
+        .set("Content-Type", "application/x-ndjson")?;
+    Ok(res)
+}
+
+/// Make the next cron run fetch the channel starting from the message given
+/// as `?id=` (or as the plain body), instead of from the last run
+pub async fn discord_watermark_set(
+    mut req: Request,
+    ctx: RouteContext<AppData>,
+) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let Some(ch_id) = ctx.param("channel") else {
+        return Response::error("Channel not found", 404);
+    };
+
+    let id = match req.url()?.query_pairs().find(|(k, _)| k == "id") {
+        Some((_, v)) => v.into_owned(),
+        None => req.text().await?.trim().to_string(),
+    };
+    let posted_at = match utils::snowflake_to_utc_datetime(&id) {
+        Ok(t) => t,
+        Err(e) => return Response::error(format!("`id` must be a message snowflake. {e}"), 400),
+    };
+
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+    kv.put(
+        &crate::kvprefix::key(&crate::discord::watermark_key(ch_id)),
+        &id,
+    )?
+    .execute()
+    .await?;
+
+    Response::from_json(&serde_json::json!({
+        "channel": ch_id,
+        "watermark": id,
+        "time": posted_at
+            .format(&time::format_description::well_known::Rfc3339)
+            .map_err(|e| worker::Error::RustError(e.to_string()))?,
+    }))
+}
+
+/// Drop the watermark of a channel, so its next run fetches the usual range
+pub async fn discord_watermark_delete(
+    req: Request,
+    ctx: RouteContext<AppData>,
+) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let Some(ch_id) = ctx.param("channel") else {
+        return Response::error("Channel not found", 404);
+    };
+
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+    kv.delete(&crate::kvprefix::key(&crate::discord::watermark_key(ch_id)))
+        .await?;
+
+    Response::from_json(&serde_json::json!({
+        "channel": ch_id,
+        "watermark": null,
+    }))
+}
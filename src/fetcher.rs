@@ -1,19 +1,224 @@
-use std::{rc::Rc, str::FromStr};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
 use anyhow::{Result, anyhow};
+use async_lock::Semaphore;
 use backon::{ExponentialBuilder, Retryable};
-use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use futures::TryStreamExt;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use web_time::Instant;
 use worker::{Cache, Fetch, Headers, RequestInit};
 
 #[derive(Debug, Clone)]
-pub struct Client {
+pub struct Client<T = WorkerTransport> {
     base_url: String,
     headers: HeaderMap,
 
+    transport: T,
+
+    /// Permit held for each request attempt, see `subrequests::Subrequests`
+    limiter: Option<Arc<Semaphore>>,
+
+    /// Shared by the clones of this client, see [`Client::with_circuit_breaker`]
+    breaker: Option<Rc<CircuitBreaker>>,
+
+    /// See [`Client::with_attempt_counter`]
+    attempts: Option<Arc<AtomicUsize>>,
+
+    /// See [`Client::with_url_guard`]
+    url_guard: Option<Rc<crate::urlguard::UrlGuard>>,
+}
+
+/// Thresholds of [`Client::with_circuit_breaker`]
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Failed attempts in a row that open the circuit for a host
+    pub threshold: u32,
+    /// Failures further apart than this don't add up
+    pub window: Duration,
+    /// How long an open circuit refuses requests before letting a probe through
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    failures: u32,
+    first_failure: Option<Instant>,
+    open_until: Option<Instant>,
+    /// A request is testing the host after the cooldown
+    probing: bool,
+}
+
+/// Per-host failure tracking. Only attempts the host answered badly or not
+/// at all count, a `404` says the host is up.
+#[derive(Debug)]
+struct CircuitBreaker {
+    config: BreakerConfig,
+    hosts: RefCell<HashMap<String, HostState>>,
+}
+
+impl CircuitBreaker {
+    /// `Err` with the time left while `host` is refused. Once the cooldown is
+    /// over, the first caller goes through as the probe and the rest wait it out.
+    fn check(&self, host: &str) -> std::result::Result<(), CircuitOpen> {
+        let mut hosts = self.hosts.borrow_mut();
+        let Some(state) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        if now < open_until || state.probing {
+            return Err(CircuitOpen {
+                host: host.to_string(),
+                retry_in: open_until.saturating_duration_since(now),
+            });
+        }
+
+        tracing::info!("Cooldown of {host} is over, probing it");
+        state.probing = true;
+        Ok(())
+    }
+
+    fn record_success(&self, host: &str) {
+        if self
+            .hosts
+            .borrow_mut()
+            .remove(host)
+            .is_some_and(|x| x.open_until.is_some())
+        {
+            tracing::info!("{host} is answering again, closing its circuit");
+        }
+    }
+
+    fn record_failure(&self, host: &str) {
+        let mut hosts = self.hosts.borrow_mut();
+        let state = hosts.entry(host.to_string()).or_default();
+        let now = Instant::now();
+
+        if state
+            .first_failure
+            .is_none_or(|x| now.duration_since(x) > self.config.window)
+        {
+            state.failures = 0;
+            state.first_failure = Some(now);
+        }
+        state.failures += 1;
+
+        if state.probing || state.failures >= self.config.threshold {
+            tracing::warn!(
+                "Opening the circuit for {host} after {} failures, for {:?}",
+                state.failures,
+                self.config.cooldown
+            );
+            state.open_until = Some(now + self.config.cooldown);
+            state.probing = false;
+        }
+    }
+}
+
+/// A response as the transport handed it over, body fully read
+#[derive(Debug, Clone)]
+pub struct TransportResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// Per-request options of [`Client::fetch_opts`]
+#[derive(Debug, Clone, Copy)]
+pub struct FetchOpts {
+    /// Look the response up in the cache and store it there. Off, the
+    /// request always goes out and the fresh response isn't stored either.
+    pub use_cache: bool,
+    /// With `use_cache` off, still store the fresh response, replacing
+    /// whatever was cached
+    pub refresh_cache: bool,
+    /// Let the transport follow redirects. Off, a redirect is answered as
+    /// is, for the client to check before following it, see [`Client::with_url_guard`].
+    pub follow_redirects: bool,
+}
+
+impl Default for FetchOpts {
+    fn default() -> Self {
+        Self {
+            use_cache: true,
+            refresh_cache: false,
+            follow_redirects: true,
+        }
+    }
+}
+
+/// What actually sends the requests of a [`Client`]. The client does the
+/// retrying and status handling on top, so those don't depend on the worker
+/// runtime: any implementation answering requests can be plugged in.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &HeaderMap,
+        body: Option<Vec<u8>>,
+        opts: FetchOpts,
+    ) -> Result<TransportResponse>;
+}
+
+/// Sends through `worker::Fetch`, keeping GET responses in the worker Cache
+#[derive(Debug, Clone)]
+pub struct WorkerTransport {
     cache: Rc<Cache>,
     cache_ttl: usize,
+    /// See [`Client::with_cache_directives`]
+    cache_directives: String,
+    /// See [`Client::with_revalidate_window`]
+    revalidate_window: usize,
+
+    max_body_bytes: usize,
+
+    /// Longest wait for the response headers, see [`ConnectTimeout`]
+    connect_timeout: Option<Duration>,
+    /// Longest wait for the next body chunk, see [`ReadStalled`]
+    read_timeout: Option<Duration>,
 }
 
+/// Cloudflare's Cache API won't store a response carrying `Set-Cookie`
+/// unless `Cache-Control` says `private=Set-Cookie`, which also strips the
+/// cookie from the stored copy. Pages of sites setting a session cookie on
+/// every response would never be cached otherwise.
+const DEFAULT_CACHE_DIRECTIVES: &str = "private=Set-Cookie";
+
+/// When a cached copy was fetched, in unix seconds, see `WorkerTransport::is_fresh`
+const FETCHED_AT_HEADER: &str = "X-Fetched-At";
+
+/// Responses larger than this are refused unless `with_max_body_bytes` says otherwise
+const DEFAULT_MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Redirects a client with a [`crate::urlguard::UrlGuard`] follows per attempt
+const MAX_REDIRECTS: usize = 5;
+
 pub struct RequestHeaders(pub Headers);
 
 impl From<&HeaderMap> for RequestHeaders {
@@ -45,7 +250,17 @@ impl TryFrom<RequestHeaders> for HeaderMap {
         let mut headers = HeaderMap::new();
 
         for (key, value) in value.entries() {
-            headers.append(HeaderName::from_str(&key)?, HeaderValue::from_str(&value)?);
+            // `from_str` would reject obs-text, `from_bytes` only control characters.
+            // A header that still doesn't convert shouldn't fail the whole response.
+            match (
+                HeaderName::from_str(&key),
+                HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    headers.append(name, value);
+                }
+                _ => tracing::debug!("Skipping unrepresentable header `{key}`"),
+            }
         }
 
         Ok(headers)
@@ -53,10 +268,12 @@ impl TryFrom<RequestHeaders> for HeaderMap {
 }
 
 #[derive(Debug)]
-struct HttpError {
+pub struct HttpError {
     status: u16,
     headers: HeaderMap,
     message: String,
+    /// Response body, for APIs that explain the failure in it
+    body: String,
 }
 
 impl std::fmt::Display for HttpError {
@@ -67,14 +284,598 @@ impl std::fmt::Display for HttpError {
 
 impl std::error::Error for HttpError {}
 
+/// The response body is, or claims to be, over the client's `max_body_bytes`
+#[derive(Debug)]
+pub struct BodyTooLarge {
+    pub limit: usize,
+    /// From `Content-Length`, if it's what gave it away
+    pub declared: Option<usize>,
+}
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.declared {
+            Some(len) => write!(
+                f,
+                "Response body of {len} bytes exceeds {} bytes",
+                self.limit
+            ),
+            None => write!(f, "Response body exceeds {} bytes", self.limit),
+        }
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// The host failed enough lately that requests to it are refused for now
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub host: String,
+    pub retry_in: Duration,
+}
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is failing, not retrying it for another {:?}",
+            self.host, self.retry_in
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// No response headers within the transport's `connect_timeout`.
+///
+/// The worker runtime has no socket-level timeouts, so this covers
+/// everything up to the headers: DNS, connecting, and the server's think time.
+#[derive(Debug)]
+pub struct ConnectTimeout {
+    pub after: Duration,
+}
+
+impl std::fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No response within {:?}", self.after)
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+/// The body stopped arriving for longer than the transport's `read_timeout`
+#[derive(Debug)]
+pub struct ReadStalled {
+    pub after: Duration,
+}
+
+impl std::fmt::Display for ReadStalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Response body stalled for {:?}", self.after)
+    }
+}
+
+impl std::error::Error for ReadStalled {}
+
+/// A redirect the client's `UrlGuard` refused to follow, or one too many
+#[derive(Debug)]
+pub struct RedirectRefused {
+    pub location: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for RedirectRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Refusing to follow redirect to {}: {}",
+            self.location, self.reason
+        )
+    }
+}
+
+impl std::error::Error for RedirectRefused {}
+
+/// `None` if `fut` is still pending after `limit`. No limit just awaits it.
+///
+/// The loser isn't cancelled on the JS side: a timed out fetch keeps running
+/// in the background, its result is only ignored.
+async fn within<F: Future>(fut: F, limit: Option<Duration>) -> Option<F::Output> {
+    let Some(limit) = limit else {
+        return Some(fut.await);
+    };
+
+    match futures::future::select(std::pin::pin!(fut), worker::Delay::from(limit)).await {
+        futures::future::Either::Left((out, _)) => Some(out),
+        futures::future::Either::Right(_) => None,
+    }
+}
+
+fn is_retryable(err: &anyhow::Error) -> bool {
+    // Too large won't shrink. A body that stalled mid-way likely stalls again,
+    // and already cost a full wait. A connect timeout is worth another go.
+    if err.is::<BodyTooLarge>()
+        || err.is::<ReadStalled>()
+        || err.is::<CircuitOpen>()
+        || err.is::<RedirectRefused>()
+    {
+        return false;
+    }
+    err.downcast_ref::<HttpError>()
+        .is_none_or(HttpError::is_retryable)
+}
+
+impl HttpError {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// Only throttling, timeouts and server errors can succeed on a later attempt
+    fn is_retryable(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS
+            || self.status == StatusCode::REQUEST_TIMEOUT
+            || (500..600).contains(&self.status)
+    }
+}
+
+impl Default for WorkerTransport {
+    fn default() -> Self {
+        Self {
+            cache: Rc::new(Cache::default()),
+            cache_ttl: 60,
+            cache_directives: DEFAULT_CACHE_DIRECTIVES.to_string(),
+            revalidate_window: 0,
+
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+
+            connect_timeout: None,
+            read_timeout: None,
+        }
+    }
+}
+
+/// Seconds to wait per a `Retry-After` value, either a number of seconds or
+/// an HTTP date. A date already past means right away.
+fn retry_after_secs(value: &str, now: time::UtcDateTime) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(secs);
+    }
+
+    // IMF-fixdate, the one format HTTP dates are sent in nowadays
+    let format = time::format_description::parse(
+        "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT",
+    )
+    .ok()?;
+    let at = time::PrimitiveDateTime::parse(value, &format)
+        .ok()?
+        .assume_utc()
+        .to_utc();
+    Some((at - now).whole_seconds().max(0) as u64)
+}
+
+/// Query parameter of [`cache_key`], unlikely to clash with a site's own
+const CACHE_KEY_PARAM: &str = "__vpm_headers";
+
+/// What the response of `url` is cached under. The same URL fetched with
+/// other headers is another entry: a page fetched with a source's secret
+/// cookie must not answer a request sent without it.
+fn cache_key(url: &str, headers: &HeaderMap) -> String {
+    if headers.is_empty() {
+        return url.to_string();
+    }
+    let Ok(mut key) = url::Url::parse(url) else {
+        return url.to_string();
+    };
+
+    // Sorted, so the order they were set in doesn't matter
+    let mut sorted = headers.iter().collect::<Vec<_>>();
+    sorted.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+    let mut hasher = DefaultHasher::new();
+    for (name, value) in sorted {
+        name.as_str().hash(&mut hasher);
+        value.as_bytes().hash(&mut hasher);
+    }
+
+    key.query_pairs_mut()
+        .append_pair(CACHE_KEY_PARAM, &format!("{:016x}", hasher.finish()));
+    key.to_string()
+}
+
+/// `Cache-Control` of a cached copy: `directives` then `max-age`
+fn cache_control(directives: &str, max_age: usize) -> String {
+    if directives.is_empty() {
+        format!("max-age={max_age}")
+    } else {
+        format!("{directives},max-age={max_age}")
+    }
+}
+
+impl WorkerTransport {
+    /// What the cached copy of a response is stored with
+    fn cache_control(&self) -> String {
+        // Stale copies are kept around for their validators
+        cache_control(
+            &self.cache_directives,
+            self.cache_ttl + self.revalidate_window,
+        )
+    }
+
+    /// `Content-Length` of the response, failing if it's over `max_body_bytes`
+    fn check_declared_len(&self, res: &worker::Response) -> Result<Option<usize>> {
+        let declared = res
+            .headers()
+            .get("Content-Length")?
+            .and_then(|x| x.parse::<usize>().ok());
+
+        match declared {
+            Some(len) if len > self.max_body_bytes => Err(anyhow::Error::new(BodyTooLarge {
+                limit: self.max_body_bytes,
+                declared,
+            })),
+            _ => Ok(declared),
+        }
+    }
+
+    /// Read the body, giving up as soon as it goes over `max_body_bytes`
+    async fn read_body(&self, res: &mut worker::Response) -> Result<Vec<u8>> {
+        let declared = self.check_declared_len(res)?;
+
+        let mut body = Vec::with_capacity(declared.unwrap_or_default());
+        let mut chunks = res.stream()?;
+        loop {
+            let Some(next) = within(chunks.try_next(), self.read_timeout).await else {
+                return Err(anyhow::Error::new(ReadStalled {
+                    after: self.read_timeout.unwrap_or_default(),
+                }));
+            };
+            let Some(chunk) = next? else {
+                break;
+            };
+
+            if body.len() + chunk.len() > self.max_body_bytes {
+                return Err(anyhow::Error::new(BodyTooLarge {
+                    limit: self.max_body_bytes,
+                    declared: None,
+                }));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(body)
+    }
+}
+
+impl Transport for WorkerTransport {
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &HeaderMap,
+        body: Option<Vec<u8>>,
+        opts: FetchOpts,
+    ) -> Result<TransportResponse> {
+        let cacheable = method == Method::GET;
+
+        let key = cache_key(url, headers);
+        let cached = if cacheable && opts.use_cache {
+            self.cache.get(key.as_str(), false).await?
+        } else {
+            None
+        };
+
+        let res = match cached {
+            Some(cached) if self.is_fresh(&cached)? => {
+                tracing::trace!("Cache HIT for {url}");
+                crate::metrics::incr("fetcher_cache_hits_total");
+                return self.read_response(cached).await;
+            }
+            Some(cached) => {
+                tracing::trace!("Cache STALE for {url}, revalidating");
+                let stale = self.read_response(cached).await?;
+                revalidate(&Network(self), method, url, headers, body, opts, stale).await?
+            }
+            None => {
+                tracing::trace!("Cache MISS for {url}");
+                crate::metrics::incr("fetcher_cache_misses_total");
+                Network(self).send(method, url, headers, body, opts).await?
+            }
+        };
+
+        // A redirect is only answered when not followed, a client following
+        // them must not get it from the cache
+        let redirect = (300..400).contains(&res.status);
+        if cacheable && !redirect && (opts.use_cache || opts.refresh_cache) {
+            self.store(&key, &res).await?;
+        }
+
+        Ok(res)
+    }
+}
+
+/// Sends through `worker::Fetch`, leaving the cache to [`WorkerTransport`]
+struct Network<'a>(&'a WorkerTransport);
+
+impl Transport for Network<'_> {
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &HeaderMap,
+        body: Option<Vec<u8>>,
+        opts: FetchOpts,
+    ) -> Result<TransportResponse> {
+        let mut init = RequestInit::new();
+        init.with_method(worker::Method::from(method.to_string()))
+            .with_headers(RequestHeaders::from(headers).into());
+        if !opts.follow_redirects {
+            init.with_redirect(worker::RequestRedirect::Manual);
+        }
+        if let Some(body) = body {
+            init.with_body(Some(
+                worker::js_sys::Uint8Array::from(body.as_slice()).into(),
+            ));
+        }
+
+        let req = worker::Request::new_with_init(url, &init)?;
+        let Some(res) = within(Fetch::Request(req).send(), self.0.connect_timeout).await else {
+            return Err(anyhow::Error::new(ConnectTimeout {
+                after: self.0.connect_timeout.unwrap_or_default(),
+            }));
+        };
+
+        self.0.read_response(res?).await
+    }
+}
+
+/// Fetch `url` again for a `stale` cached copy, conditional on its
+/// validators. A `304` gives `stale` back, anything else the new response.
+async fn revalidate<T: Transport>(
+    transport: &T,
+    method: Method,
+    url: &str,
+    headers: &HeaderMap,
+    body: Option<Vec<u8>>,
+    opts: FetchOpts,
+    stale: TransportResponse,
+) -> Result<TransportResponse> {
+    let mut headers = headers.clone();
+    add_validators(&mut headers, &stale.headers);
+
+    let res = transport.send(method, url, &headers, body, opts).await?;
+    if res.status != StatusCode::NOT_MODIFIED {
+        return Ok(res);
+    }
+
+    tracing::trace!("Cache REVALIDATED for {url}");
+    crate::metrics::incr("fetcher_cache_revalidated_total");
+    Ok(stale)
+}
+
+/// Make `headers` conditional on the validators of `stale`. The ETag is
+/// the exact one, so the date is only sent when there's no ETag.
+fn add_validators(headers: &mut HeaderMap, stale: &HeaderMap) {
+    if let Some(etag) = stale.get(http::header::ETAG) {
+        headers.insert(http::header::IF_NONE_MATCH, etag.clone());
+    } else if let Some(modified) = stale.get(http::header::LAST_MODIFIED) {
+        headers.insert(http::header::IF_MODIFIED_SINCE, modified.clone());
+    }
+}
+
+impl WorkerTransport {
+    /// Whether a cached copy is younger than `cache_ttl`. Copies stored
+    /// without [`FETCHED_AT_HEADER`] are only kept for `cache_ttl` anyway.
+    fn is_fresh(&self, cached: &worker::Response) -> Result<bool> {
+        let Some(fetched_at) = cached
+            .headers()
+            .get(FETCHED_AT_HEADER)?
+            .and_then(|x| x.parse::<i64>().ok())
+        else {
+            return Ok(true);
+        };
+
+        let age = time::UtcDateTime::now().unix_timestamp() - fetched_at;
+        Ok(age < self.cache_ttl as i64)
+    }
+
+    /// Put a copy of `res` in the cache under `key`, marked as fetched now
+    async fn store(&self, key: &str, res: &TransportResponse) -> Result<()> {
+        let mut copy = worker::Response::from_bytes(res.body.clone())?
+            .with_status(res.status)
+            .with_headers(RequestHeaders::from(&res.headers).into());
+
+        copy.headers_mut()
+            .set("Cache-Control", &self.cache_control())?;
+        copy.headers_mut().set(
+            FETCHED_AT_HEADER,
+            &time::UtcDateTime::now().unix_timestamp().to_string(),
+        )?;
+        self.cache.put(key, copy).await?;
+
+        Ok(())
+    }
+
+    async fn read_response(&self, mut res: worker::Response) -> Result<TransportResponse> {
+        Ok(TransportResponse {
+            status: res.status_code(),
+            headers: RequestHeaders(res.headers().clone()).try_into()?,
+            body: self.read_body(&mut res).await?,
+        })
+    }
+}
+
 impl Client {
     pub fn new(base_url: impl ToString) -> Self {
+        Self::with_transport(base_url, WorkerTransport::default())
+    }
+
+    pub fn with_cache_ttl(self, ttl: usize) -> Self {
+        Self {
+            transport: WorkerTransport {
+                cache_ttl: ttl,
+                ..self.transport
+            },
+            ..self
+        }
+    }
+
+    /// Directives stored in `Cache-Control` ahead of `max-age` on cached
+    /// responses, `private=Set-Cookie` by default. Empty leaves only `max-age`.
+    pub fn with_cache_directives(self, directives: impl ToString) -> Self {
+        Self {
+            transport: WorkerTransport {
+                cache_directives: directives.to_string(),
+                ..self.transport
+            },
+            ..self
+        }
+    }
+
+    /// Keep cached responses `secs` past their `cache_ttl`. Once stale, a
+    /// copy is revalidated with `If-None-Match` or `If-Modified-Since`, and a
+    /// `304` serves it again instead of downloading the body. 0, the default,
+    /// drops copies as soon as they expire.
+    pub fn with_revalidate_window(self, secs: usize) -> Self {
+        Self {
+            transport: WorkerTransport {
+                revalidate_window: secs,
+                ..self.transport
+            },
+            ..self
+        }
+    }
+
+    /// Fail with [`BodyTooLarge`] on responses over `max_body_bytes`, as
+    /// soon as `Content-Length` or the bytes read so far say so. 16 MiB by default.
+    pub fn with_max_body_bytes(self, max_body_bytes: usize) -> Self {
+        Self {
+            transport: WorkerTransport {
+                max_body_bytes,
+                ..self.transport
+            },
+            ..self
+        }
+    }
+
+    /// Drop the cached response of `endpoint`, so the next fetch of it goes out
+    pub async fn evict(&self, endpoint: &str) -> Result<()> {
+        let url = format!("{}{endpoint}", &self.base_url);
+        self.transport
+            .cache
+            .delete(cache_key(&url, &self.headers).as_str(), false)
+            .await?;
+        Ok(())
+    }
+
+    /// Fail an attempt with [`ConnectTimeout`] when the response headers take
+    /// longer than `timeout`. Retried like a server error.
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self {
+        Self {
+            transport: WorkerTransport {
+                connect_timeout: Some(timeout),
+                ..self.transport
+            },
+            ..self
+        }
+    }
+
+    /// Fail with [`ReadStalled`] when no body chunk arrives for `timeout`.
+    /// Not retried, see `is_retryable`.
+    pub fn with_read_timeout(self, timeout: Duration) -> Self {
+        Self {
+            transport: WorkerTransport {
+                read_timeout: Some(timeout),
+                ..self.transport
+            },
+            ..self
+        }
+    }
+}
+
+impl<T: Transport> Client<T> {
+    /// One try at `url`, without retrying
+    async fn attempt(&self, url: &str, opts: FetchOpts) -> Result<(HeaderMap, Vec<u8>)> {
+        // Per attempt, so waiting out a backoff doesn't hold the permit
+        let _permit = match &self.limiter {
+            Some(limiter) => Some(limiter.acquire_arc().await),
+            None => None,
+        };
+
+        let opts = FetchOpts {
+            follow_redirects: self.url_guard.is_none(),
+            ..opts
+        };
+        let mut url = url.to_string();
+        let mut redirects = 0;
+
+        let res = loop {
+            if let Some(attempts) = &self.attempts {
+                attempts.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let res = self
+                .transport
+                .send(Method::GET, &url, &self.headers, None, opts)
+                .await?;
+
+            let location = res
+                .headers
+                .get(http::header::LOCATION)
+                .and_then(|x| x.to_str().ok());
+            let (Some(guard), Some(location), true) =
+                (&self.url_guard, location, (300..400).contains(&res.status))
+            else {
+                break res;
+            };
+
+            let next = url::Url::parse(&url)?.join(location)?;
+            let refused = |reason: String| RedirectRefused {
+                location: next.to_string(),
+                reason,
+            };
+            if redirects == MAX_REDIRECTS {
+                return Err(anyhow::Error::new(refused(format!(
+                    "more than {MAX_REDIRECTS} redirects"
+                ))));
+            }
+            guard
+                .check(next.as_str())
+                .map_err(|e| anyhow::Error::new(refused(e.to_string())))?;
+
+            tracing::trace!("Following redirect from {url} to {next}");
+            url = next.into();
+            redirects += 1;
+        };
+
+        if !(200..300).contains(&res.status) {
+            let src = HttpError {
+                status: res.status,
+                headers: res.headers,
+                message: format!("Request failed with status {}", res.status),
+                body: String::from_utf8_lossy(&res.body).into_owned(),
+            };
+            return Err(anyhow::Error::new(src));
+        }
+
+        Ok((res.headers, res.body))
+    }
+
+    pub fn with_transport(base_url: impl ToString, transport: T) -> Self {
         Self {
             base_url: base_url.to_string(),
             headers: HeaderMap::new(),
 
-            cache: Rc::new(Cache::default()),
-            cache_ttl: 60,
+            transport,
+
+            limiter: None,
+            breaker: None,
+            attempts: None,
+            url_guard: None,
         }
     }
 
@@ -82,61 +883,108 @@ impl Client {
         Self { headers, ..self }
     }
 
-    pub fn with_cache_ttl(self, ttl: usize) -> Self {
+    pub fn with_limiter(self, limiter: Arc<Semaphore>) -> Self {
         Self {
-            cache_ttl: ttl,
+            limiter: Some(limiter),
+            ..self
+        }
+    }
+
+    /// Send within the invocation's subrequest limits, counting every attempt
+    pub fn with_subrequests(self, subrequests: &crate::subrequests::Subrequests) -> Self {
+        self.with_limiter(subrequests.limiter())
+            .with_attempt_counter(subrequests.counter())
+    }
+
+    /// Add one to `counter` for every request sent, retries included, e.g.
+    /// to stay within Cloudflare's subrequest limit
+    pub fn with_attempt_counter(self, counter: Arc<AtomicUsize>) -> Self {
+        Self {
+            attempts: Some(counter),
+            ..self
+        }
+    }
+
+    /// Follow redirects only to URLs `guard` lets through, checking each hop.
+    /// For URLs a caller supplied: a public page redirecting to a private
+    /// host would get around checking the URL itself. Refused redirects fail
+    /// with [`RedirectRefused`] and aren't retried.
+    pub fn with_url_guard(self, guard: crate::urlguard::UrlGuard) -> Self {
+        Self {
+            url_guard: Some(Rc::new(guard)),
+            ..self
+        }
+    }
+
+    /// Stop sending requests to a host that keeps failing, see [`BreakerConfig`].
+    /// The clones of this client share the state.
+    ///
+    /// Checked before every attempt, so requests already retrying give up too
+    /// once the circuit opens. Refusals fail with [`CircuitOpen`] right away.
+    pub fn with_circuit_breaker(self, config: BreakerConfig) -> Self {
+        Self {
+            breaker: Some(Rc::new(CircuitBreaker {
+                config,
+                hosts: RefCell::new(HashMap::new()),
+            })),
             ..self
         }
     }
 
     pub async fn fetch(&self, endpoint: &str) -> Result<Vec<u8>> {
+        self.fetch_opts(endpoint, FetchOpts::default()).await
+    }
+
+    /// [`Self::fetch`] with per-request options, e.g. skipping the cache for
+    /// one request without building another client
+    pub async fn fetch_opts(&self, endpoint: &str, opts: FetchOpts) -> Result<Vec<u8>> {
+        self.fetch_with_headers(endpoint, opts)
+            .await
+            .map(|(_, body)| body)
+    }
+
+    /// [`Self::fetch_opts`], keeping the response headers alongside the body
+    async fn fetch_with_headers(
+        &self,
+        endpoint: &str,
+        opts: FetchOpts,
+    ) -> Result<(HeaderMap, Vec<u8>)> {
         let url = format!("{}{endpoint}", &self.base_url);
+        let host = url::Url::parse(&url)
+            .ok()
+            .and_then(|x| x.host_str().map(str::to_string))
+            .unwrap_or_default();
+
         let fetchcall = || async {
-            let mut res = if let Some(cached) = self.cache.get(&url, false).await? {
-                tracing::trace!("Cache HIT for {url}");
-                cached
-            } else {
-                tracing::trace!("Cache MISS for {url}");
-                let req = worker::Request::new_with_init(
-                    &url,
-                    RequestInit::new().with_headers(self.headers.clone().into()),
-                )?;
-                let mut res = Fetch::Request(req).send().await?;
-                let mut cloned_res = res.cloned()?;
-
-                cloned_res.headers_mut().set(
-                    "Cache-Control",
-                    &format!("private=Set-Cookie,max-age={}", self.cache_ttl),
-                )?;
-                self.cache.put(&url, cloned_res.cloned()?).await?;
-
-                res
-            };
+            if let Some(breaker) = &self.breaker {
+                breaker.check(&host)?;
+            }
+
+            let res = self.attempt(&url, opts).await;
 
-            if res.status_code() != StatusCode::OK {
-                let src = HttpError {
-                    status: res.status_code(),
-                    headers: RequestHeaders(res.headers().clone()).try_into()?,
-                    message: format!("Request failed with status {}", res.status_code()),
-                };
-                return Err(anyhow::Error::new(src));
+            if let Some(breaker) = &self.breaker {
+                match &res {
+                    Err(e) if is_retryable(e) => breaker.record_failure(&host),
+                    _ => breaker.record_success(&host),
+                }
             }
 
-            Ok(res.bytes().await?)
+            res
         };
 
         let res = fetchcall
             .retry(ExponentialBuilder::default().with_jitter().with_max_times(5).with_min_delay(std::time::Duration::from_secs(1)))
+            .when(is_retryable)
             .adjust(|err, dur| match err.downcast_ref::<HttpError>() {
                 Some(v) => {
                     if v.status == StatusCode::TOO_MANY_REQUESTS {
-                        let retry_after = if let Some(retry_after) = v.headers.get("Retry-After") {
-                            // Parse the Retry-After header and adjust the backoff
-                            let retry_after = retry_after.to_str().unwrap_or("30");
-                            retry_after.parse::<u64>().unwrap_or(30)
-                        } else {
-                            30u64
-                        };
+                        // Parse the Retry-After header and adjust the backoff
+                        let retry_after = v
+                            .headers
+                            .get("Retry-After")
+                            .and_then(|x| x.to_str().ok())
+                            .and_then(|x| retry_after_secs(x, time::UtcDateTime::now()))
+                            .unwrap_or(30);
 
                         if retry_after > 60 * 15 {
                             // Retry after is more than 15 mins. Maybe abort
@@ -171,4 +1019,434 @@ impl Client {
     pub async fn get_text(&self, endpoint: &str) -> Result<String> {
         Ok(String::from_utf8(self.fetch(endpoint).await?)?)
     }
+
+    /// Like [`Self::get_text`], but transcoded to UTF-8 from the charset the
+    /// `Content-Type` header or a `<meta charset>` names. Bodies naming none
+    /// are decoded as UTF-8, invalid bytes replaced instead of failing.
+    pub async fn get_text_lossy(&self, endpoint: &str) -> Result<String> {
+        let (headers, body) = self
+            .fetch_with_headers(endpoint, FetchOpts::default())
+            .await?;
+
+        Ok(decode_text(&headers, &body))
+    }
+}
+
+static META_CHARSET: std::sync::LazyLock<regex::bytes::Regex> = std::sync::LazyLock::new(|| {
+    regex::bytes::Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?([\w-]+)"#).unwrap()
+});
+
+/// Where a page declares its charset, HTML puts it in the first 1024 bytes
+const META_SNIFF_BYTES: usize = 1024;
+
+/// See [`Client::get_text_lossy`]
+fn decode_text(headers: &HeaderMap, body: &[u8]) -> String {
+    let from_header = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| {
+            x.split(';')
+                .filter_map(|x| x.trim().split_once('='))
+                .find(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+                .map(|(_, v)| v.trim().trim_matches('"').as_bytes().to_vec())
+        });
+    let from_meta = || {
+        META_CHARSET
+            .captures(&body[..body.len().min(META_SNIFF_BYTES)])
+            .map(|x| x[1].to_vec())
+    };
+
+    // A header naming a charset encoding_rs doesn't know is no better than
+    // none, so the page's own declaration gets its say then
+    match from_header
+        .and_then(|x| encoding_rs::Encoding::for_label(&x))
+        .or_else(|| from_meta().and_then(|x| encoding_rs::Encoding::for_label(&x)))
+    {
+        Some(encoding) => encoding.decode(body).0.into_owned(),
+        None => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Answers with scripted responses in order, recording the requests it got
+    #[derive(Default)]
+    struct MockTransport {
+        responses: RefCell<VecDeque<TransportResponse>>,
+        requests: RefCell<Vec<(String, HeaderMap)>>,
+    }
+
+    impl MockTransport {
+        fn new(responses: impl IntoIterator<Item = TransportResponse>) -> Self {
+            Self {
+                responses: RefCell::new(responses.into_iter().collect()),
+                ..Default::default()
+            }
+        }
+
+        fn request_count(&self) -> usize {
+            self.requests.borrow().len()
+        }
+    }
+
+    impl Transport for &MockTransport {
+        async fn send(
+            &self,
+            _method: Method,
+            url: &str,
+            headers: &HeaderMap,
+            _body: Option<Vec<u8>>,
+            _opts: FetchOpts,
+        ) -> Result<TransportResponse> {
+            self.requests
+                .borrow_mut()
+                .push((url.to_string(), headers.clone()));
+            self.responses
+                .borrow_mut()
+                .pop_front()
+                .ok_or_else(|| anyhow!("No response scripted for {url}"))
+        }
+    }
+
+    fn response(status: u16, headers: &[(&'static str, &str)], body: &str) -> TransportResponse {
+        TransportResponse {
+            status,
+            headers: headers
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        HeaderName::from_static(k),
+                        HeaderValue::from_str(v).unwrap(),
+                    )
+                })
+                .collect(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn status_of(err: &anyhow::Error) -> Option<u16> {
+        err.downcast_ref::<HttpError>().map(HttpError::status)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ok_response_returns_body() {
+        let mock = MockTransport::new([response(200, &[], "hello")]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        assert_eq!(client.fetch("/a").await.unwrap(), b"hello");
+        assert_eq!(mock.requests.borrow()[0].0, "https://example.com/a");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn server_error_is_retried() {
+        let mock = MockTransport::new([response(503, &[], ""), response(200, &[], "ok")]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        assert_eq!(client.fetch("/a").await.unwrap(), b"ok");
+        assert_eq!(mock.request_count(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn non_ok_status_fails_with_http_error() {
+        let mock = MockTransport::new([response(404, &[], "gone")]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        let err = client.fetch("/a").await.unwrap_err();
+        assert_eq!(status_of(&err), Some(404));
+        assert_eq!(err.downcast_ref::<HttpError>().unwrap().body(), "gone");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_error_fails_after_one_attempt() {
+        let mock = MockTransport::new([response(403, &[], ""), response(200, &[], "ok")]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        let err = client.fetch("/a").await.unwrap_err();
+        assert_eq!(status_of(&err), Some(403));
+        assert_eq!(mock.request_count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn service_unavailable_retries_until_exhausted() {
+        let mock = MockTransport::new(std::iter::repeat_n(response(503, &[], ""), 10));
+        let client = Client::with_transport("https://example.com", &mock);
+
+        let err = client.fetch("/a").await.unwrap_err();
+        assert_eq!(status_of(&err), Some(503));
+        // The first attempt and 5 retries
+        assert_eq!(mock.request_count(), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn too_many_requests_waits_retry_after() {
+        let mock = MockTransport::new([
+            response(429, &[("retry-after", "7")], ""),
+            response(200, &[], "ok"),
+        ]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(client.fetch("/a").await.unwrap(), b"ok");
+        assert!(start.elapsed() >= Duration::from_secs(7));
+        assert_eq!(mock.request_count(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn too_many_requests_waits_retry_after_date() {
+        let format = time::format_description::parse(
+            "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT",
+        )
+        .unwrap();
+        let at = (time::UtcDateTime::now() + time::Duration::seconds(10))
+            .format(&format)
+            .unwrap();
+        let mock = MockTransport::new([
+            response(429, &[("retry-after", &at)], ""),
+            response(200, &[], "ok"),
+        ]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(client.fetch("/a").await.unwrap(), b"ok");
+        // Whole seconds in the date, and the clock moved on a little since
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_secs(8) && waited < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn retry_after_reads_seconds_and_dates() {
+        // Wed, 21 Oct 2015 07:28:00 GMT
+        let date = time::UtcDateTime::from_unix_timestamp(1_445_412_480).unwrap();
+
+        assert_eq!(retry_after_secs(" 120 ", date), Some(120));
+        assert_eq!(
+            retry_after_secs(
+                "Wed, 21 Oct 2015 07:28:00 GMT",
+                date - time::Duration::seconds(90)
+            ),
+            Some(90)
+        );
+        assert_eq!(
+            retry_after_secs(
+                "Wed, 21 Oct 2015 07:28:00 GMT",
+                date + time::Duration::hours(1)
+            ),
+            Some(0)
+        );
+        assert_eq!(retry_after_secs("soon", date), None);
+    }
+
+    fn content_type(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn decode_text_uses_header_charset() {
+        let headers = content_type("text/html; charset=ISO-8859-1");
+
+        assert_eq!(decode_text(&headers, b"caf\xe9"), "café");
+    }
+
+    #[test]
+    fn decode_text_falls_back_to_meta_charset() {
+        // "日本" in Shift-JIS
+        let sjis = b"<meta charset=\"Shift_JIS\"><p>\x93\xfa\x96\x7b</p>";
+        assert_eq!(
+            decode_text(&HeaderMap::new(), sjis),
+            "<meta charset=\"Shift_JIS\"><p>日本</p>"
+        );
+
+        // A label encoding_rs doesn't know doesn't hide the page's own
+        let latin1 =
+            b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-1\">caf\xe9";
+        assert!(decode_text(&content_type("text/html; charset=bogus"), latin1).ends_with("café"));
+    }
+
+    #[test]
+    fn decode_text_undeclared_is_lossy_utf8() {
+        assert_eq!(
+            decode_text(&HeaderMap::new(), b"caf\xe9 ok"),
+            "caf\u{FFFD} ok"
+        );
+        assert_eq!(
+            decode_text(&content_type("text/html"), "café".as_bytes()),
+            "café"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_success_statuses_are_ok() {
+        let mock = MockTransport::new([response(204, &[], ""), response(206, &[], "part")]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        assert_eq!(client.fetch("/a").await.unwrap(), b"");
+        assert_eq!(client.fetch("/b").await.unwrap(), b"part");
+        assert_eq!(mock.request_count(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_after_over_15_minutes_gives_up() {
+        let mock = MockTransport::new([response(429, &[("retry-after", "3600")], "")]);
+        let client = Client::with_transport("https://example.com", &mock);
+
+        let err = client.fetch("/a").await.unwrap_err();
+        assert_eq!(status_of(&err), Some(429));
+        assert_eq!(mock.request_count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn guarded_redirect_to_private_host_is_refused() {
+        let mock = MockTransport::new([response(
+            302,
+            &[("location", "http://169.254.169.254/latest/meta-data/")],
+            "",
+        )]);
+        let client = Client::with_transport("https://example.com", &mock)
+            .with_url_guard(crate::urlguard::UrlGuard::default());
+
+        let err = client.fetch("/a").await.unwrap_err();
+        assert!(err.is::<RedirectRefused>());
+        assert_eq!(mock.request_count(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn guarded_redirect_to_public_host_is_followed() {
+        let mock = MockTransport::new([
+            response(301, &[("location", "https://other.example.net/b")], ""),
+            response(302, &[("location", "/c")], ""),
+            response(200, &[], "ok"),
+        ]);
+        let client = Client::with_transport("https://example.com", &mock)
+            .with_url_guard(crate::urlguard::UrlGuard::default());
+
+        assert_eq!(client.fetch("/a").await.unwrap(), b"ok");
+        let urls = mock
+            .requests
+            .borrow()
+            .iter()
+            .map(|(url, _)| url.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/a",
+                "https://other.example.net/b",
+                "https://other.example.net/c"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn circuit_opens_after_threshold() {
+        let mock = MockTransport::new(std::iter::repeat_n(response(503, &[], ""), 10));
+        let client = Client::with_transport("https://example.com", &mock).with_circuit_breaker(
+            BreakerConfig {
+                threshold: 2,
+                ..Default::default()
+            },
+        );
+
+        let err = client.fetch("/a").await.unwrap_err();
+        assert!(err.is::<CircuitOpen>());
+        assert_eq!(mock.request_count(), 2);
+
+        // Refused without sending anything
+        let err = client.fetch("/b").await.unwrap_err();
+        assert!(err.is::<CircuitOpen>());
+        assert_eq!(mock.request_count(), 2);
+    }
+
+    #[test]
+    fn cache_control_puts_directives_before_max_age() {
+        assert_eq!(
+            cache_control(DEFAULT_CACHE_DIRECTIVES, 360),
+            "private=Set-Cookie,max-age=360"
+        );
+        assert_eq!(cache_control("", 60), "max-age=60");
+    }
+
+    const MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    #[tokio::test]
+    async fn not_modified_serves_stale_copy() {
+        let mock = MockTransport::new([response(304, &[], "")]);
+        let stale = response(200, &[("last-modified", MODIFIED)], "cached");
+
+        let res = revalidate(
+            &&mock,
+            Method::GET,
+            "https://example.com/a",
+            &HeaderMap::new(),
+            None,
+            FetchOpts::default(),
+            stale,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.body, b"cached");
+        let requests = mock.requests.borrow();
+        let headers = &requests[0].1;
+        assert_eq!(headers[http::header::IF_MODIFIED_SINCE], MODIFIED);
+        assert!(!headers.contains_key(http::header::IF_NONE_MATCH));
+    }
+
+    #[tokio::test]
+    async fn modified_replaces_stale_copy() {
+        let mock = MockTransport::new([response(200, &[], "fresh")]);
+        let stale = response(
+            200,
+            &[("etag", "\"v1\""), ("last-modified", MODIFIED)],
+            "cached",
+        );
+
+        let res = revalidate(
+            &&mock,
+            Method::GET,
+            "https://example.com/a",
+            &HeaderMap::new(),
+            None,
+            FetchOpts::default(),
+            stale,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.body, b"fresh");
+        // The ETag is exact, the date isn't sent along with it
+        let requests = mock.requests.borrow();
+        let headers = &requests[0].1;
+        assert_eq!(headers[http::header::IF_NONE_MATCH], "\"v1\"");
+        assert!(!headers.contains_key(http::header::IF_MODIFIED_SINCE));
+    }
+
+    #[test]
+    fn cache_key_depends_on_headers() {
+        let url = "https://example.com/list/";
+        let cookie = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(http::header::COOKIE, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        assert_eq!(cache_key(url, &HeaderMap::new()), url);
+        assert_ne!(cache_key(url, &cookie("session=a")), url);
+        assert_ne!(
+            cache_key(url, &cookie("session=a")),
+            cache_key(url, &cookie("session=b"))
+        );
+        assert_eq!(
+            cache_key(url, &cookie("session=a")),
+            cache_key(url, &cookie("session=a"))
+        );
+    }
 }
@@ -0,0 +1,63 @@
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::AppData;
+use crate::discord::DiscordClient;
+
+/// Shallow check: KV is reachable and the playlist config loads and validates.
+///
+/// `?deep=1` also makes an authenticated Discord call, to catch a revoked
+/// token. Left out by default so monitors don't eat into the rate limit, and
+/// needs auth so nobody else can spend it. Its result is never cached.
+pub async fn health_get(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    // The same loaded config the handlers use, so it's judged as they see it:
+    // validated and with its secrets resolved, not just parsed
+    let (kv_ok, config_ok) = match ctx.data.config(&ctx.env).await {
+        Ok(config) => (true, config.playlist().is_ok()),
+        Err(e) => {
+            tracing::warn!("Health check config load failed: {e:?}");
+            (false, false)
+        }
+    };
+
+    let deep = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "deep" && (v == "1" || v == "true"));
+
+    if deep && let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let mut body = serde_json::json!({
+        "kv_ok": kv_ok,
+        "config_ok": config_ok,
+    });
+    let mut healthy = kv_ok && config_ok;
+
+    if deep {
+        let discord_ok = match DiscordClient::from_env(&ctx.env, &ctx.data.subrequests) {
+            Ok(client) => match client.get_current_user().await {
+                Ok(_) => true,
+                Err(e) => {
+                    tracing::warn!("Health check Discord call failed: {e}");
+                    false
+                }
+            },
+            Err(e) => {
+                tracing::warn!("Health check Discord client init failed: {e}");
+                false
+            }
+        };
+
+        body["discord_ok"] = discord_ok.into();
+        healthy &= discord_ok;
+    }
+
+    let status = if healthy { 200 } else { 503 };
+
+    let mut res = Response::from_json(&body)?.with_status(status);
+    if deep {
+        res.headers_mut().set("Cache-Control", "no-store")?;
+    }
+    Ok(res)
+}
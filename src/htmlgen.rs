@@ -37,36 +37,135 @@ impl<T: ToString> From<(T, T)> for Nav {
     }
 }
 
-pub fn gen_plaintext(str: impl AsRef<str>) -> Result<String> {
+/// Size summary shown next to the subtitle of a plaintext page
+pub struct TextStats {
+    line_count: usize,
+    byte_size: usize,
+}
+
+impl TextStats {
+    pub fn from_text(text: impl AsRef<str>) -> Self {
+        let text = text.as_ref();
+
+        Self {
+            line_count: text.lines().filter(|x| !x.trim().is_empty()).count(),
+            byte_size: text.len(),
+        }
+    }
+}
+
+/// Render the text page. `custom` replaces the embedded `text.jinja` (it can
+/// still extend `base.jinja`); if it doesn't compile or render, the embedded
+/// one is used instead.
+pub fn gen_plaintext(
+    str: impl AsRef<str>,
+    stats: Option<TextStats>,
+    custom: Option<&str>,
+) -> Result<String> {
     let mut renderenv = minijinja::Environment::new();
     minijinja_embed::load_templates!(&mut renderenv);
 
-    let template = renderenv
-        .get_template("text.jinja")
-        .expect("Failed loading links template");
+    let has_custom = custom.is_some_and(|src| {
+        renderenv
+            .add_template("custom_text.jinja", src)
+            .map_err(|e| tracing::warn!("Ignoring config_template_text, it doesn't compile: {e}"))
+            .is_ok()
+    });
     let renderctx = minijinja::context! {
         title => "Text",
         subtitle => "Text here",
         text => str.as_ref()
     };
+    let renderctx = match stats {
+        Some(stats) => minijinja::context! {
+            line_count => stats.line_count,
+            byte_size => stats.byte_size,
+            ..renderctx
+        },
+        None => renderctx,
+    };
+
+    if has_custom {
+        match renderenv
+            .get_template("custom_text.jinja")
+            .and_then(|x| x.render(&renderctx))
+        {
+            Ok(page) => return Ok(page),
+            Err(e) => tracing::warn!("Ignoring config_template_text, it failed to render: {e}"),
+        }
+    }
+
+    let template = renderenv
+        .get_template("text.jinja")
+        .expect("Failed loading links template");
 
     Ok(template
         .render(renderctx)
         .expect("Failed to render template"))
 }
 
-pub fn gen_linkpage(navs: Vec<Nav>) -> Result<String> {
+/// Page selection for link pages, read from `?page=N&per=M`
+#[derive(Clone, Copy, Debug)]
+pub struct Pagination {
+    /// 1-based page number
+    pub page: usize,
+    pub per: usize,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self { page: 1, per: 100 }
+    }
+}
+
+impl Pagination {
+    pub fn from_url(url: &url::Url) -> Result<Self> {
+        let mut pagination = Self::default();
+
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "page" => pagination.page = v.parse()?,
+                "per" => pagination.per = v.parse()?,
+                _ => {}
+            }
+        }
+
+        if pagination.page == 0 || pagination.per == 0 {
+            return Err(anyhow::anyhow!("`page` and `per` must be non-zero"));
+        }
+
+        Ok(pagination)
+    }
+
+    fn href(&self, page: usize) -> String {
+        format!("?page={page}&per={}", self.per)
+    }
+}
+
+pub fn gen_linkpage(navs: Vec<Nav>, pagination: Pagination) -> Result<String> {
     let mut renderenv = minijinja::Environment::new();
     minijinja_embed::load_templates!(&mut renderenv);
 
     let template = renderenv
         .get_template("links.jinja")
         .expect("Failed loading links template");
+
+    let total = navs.len();
+    let page_count = total.div_ceil(pagination.per).max(1);
+    let page = pagination.page.min(page_count);
+
     let renderctx = minijinja::context! {
         title => "Text",
         subtitle => "Text here",
+        total => total,
+        page => page,
+        page_count => page_count,
+        prev_href => (page > 1).then(|| pagination.href(page - 1)),
+        next_href => (page < page_count).then(|| pagination.href(page + 1)),
         navigation => navs
             .iter()
+            .skip((page - 1) * pagination.per)
+            .take(pagination.per)
             .map(|x| {
                 minijinja::context! {
                     href => x.href,
@@ -80,3 +179,20 @@ pub fn gen_linkpage(navs: Vec<Nav>) -> Result<String> {
         .render(renderctx)
         .expect("Failed to render template"))
 }
+
+/// Routes linked from the landing page, as `(href, description)`
+pub const INDEX_ROUTES: &[(&str, &str)] = &[
+    ("/playlist", "Playlists"),
+    ("/kv", "KV entries"),
+    ("/health", "Health check"),
+];
+
+pub fn gen_indexpage() -> Result<String> {
+    gen_linkpage(
+        INDEX_ROUTES
+            .iter()
+            .map(|(href, text)| Nav::new(href, text))
+            .collect_vec(),
+        Pagination::default(),
+    )
+}
@@ -1,13 +1,66 @@
+use std::io::{Read, Write};
+
 use anyhow::Result;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use worker::KvStore;
 
+/// Every gzip stream starts with these, and no text value we store does
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Storage [`KvCache`] goes through. `worker::KvStore` in the worker, but
+/// anything able to hold bytes by key (a `HashMap` say) can stand in for it
+/// where there's no worker runtime.
+#[allow(async_fn_in_trait)]
+pub trait KvBackend {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// `ttl` of `None` keeps the value forever
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Option<u64>) -> Result<()>;
+    async fn delete(&self, key: &str) -> Result<()>;
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+impl KvBackend for KvStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        KvStore::get(self, &crate::kvprefix::key(key))
+            .bytes()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))
+    }
+
+    async fn put(&self, key: &str, value: Vec<u8>, ttl: Option<u64>) -> Result<()> {
+        let put = self
+            .put_bytes(&crate::kvprefix::key(key), &value)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?;
+        let put = match ttl {
+            Some(ttl) => put.expiration_ttl(ttl),
+            None => put,
+        };
+
+        put.execute()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to put kv: {e:?}"))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        KvStore::delete(self, &crate::kvprefix::key(key))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete kv: {e:?}"))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        crate::kvmanager::list_keys(self, Some(prefix))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to list kv: {e:?}"))
+    }
+}
+
 #[derive(Clone)]
-pub struct KvCache {
-    kv: KvStore,
+pub struct KvCache<B = KvStore> {
+    kv: B,
 }
 
-impl KvCache {
-    pub fn new(kv: KvStore) -> Self {
+impl<B: KvBackend> KvCache<B> {
+    pub fn new(kv: B) -> Self {
         Self { kv }
     }
 
@@ -15,32 +68,28 @@ impl KvCache {
     where
         T: serde::de::DeserializeOwned,
     {
-        self.kv
-            .get(key.as_ref())
-            .json()
-            .await
-            .map_err(|e: worker::KvError| anyhow::anyhow!("Failed to get kv: {e:?}"))
+        match self.kv.get(key.as_ref()).await? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
     }
 
     pub async fn get_text(&self, key: impl AsRef<str>) -> Result<Option<String>> {
-        self.kv
-            .get(key.as_ref())
-            .text()
-            .await
-            .map_err(|e: worker::KvError| anyhow::anyhow!("Failed to get kv: {e:?}"))
+        match self.kv.get(key.as_ref()).await? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            None => Ok(None),
+        }
     }
 
     pub async fn set<T>(&self, key: impl AsRef<str>, value: T, ttl: u64) -> Result<()>
     where
         T: serde::ser::Serialize,
     {
-        self.kv
-            .put(key.as_ref(), value)
-            .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?
-            .expiration_ttl(ttl) // 1 week should be fine. No one change stuff that much, right?
-            .execute()
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to put kv: {e:?}"))
+        let value = serde_json::to_vec(&value)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?;
+
+        // 1 week should be fine. No one change stuff that much, right?
+        self.kv.put(key.as_ref(), value, Some(ttl)).await
     }
 
     pub async fn set_text(
@@ -50,11 +99,138 @@ impl KvCache {
         ttl: u64,
     ) -> Result<()> {
         self.kv
-            .put(key.as_ref(), value.to_string())
-            .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?
-            .expiration_ttl(ttl) // 1 week should be fine. No one change stuff that much, right?
-            .execute()
+            .put(key.as_ref(), value.to_string().into_bytes(), Some(ttl))
             .await
-            .map_err(|e| anyhow::anyhow!("Failed to put kv: {e:?}"))
+    }
+
+    pub async fn delete(&self, key: impl AsRef<str>) -> Result<()> {
+        self.kv.delete(key.as_ref()).await
+    }
+
+    /// Gzip `value` before storing it. `ttl` of `None` keeps it forever.
+    pub async fn set_compressed(
+        &self,
+        key: impl AsRef<str>,
+        value: &str,
+        ttl: Option<u64>,
+    ) -> Result<()> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(value.as_bytes())?;
+        let compressed = encoder.finish()?;
+
+        self.kv.put(key.as_ref(), compressed, ttl).await
+    }
+
+    /// Read a value written by either `set_compressed` or a plain text put
+    pub async fn get_compressed(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let Some(bytes) = self.kv.get(key.as_ref()).await? else {
+            return Ok(None);
+        };
+
+        if !bytes.starts_with(&GZIP_MAGIC) {
+            return Ok(Some(String::from_utf8(bytes)?));
+        }
+
+        let mut value = String::new();
+        GzDecoder::new(bytes.as_slice()).read_to_string(&mut value)?;
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+
+    /// Values by key, TTLs ignored
+    #[derive(Default)]
+    struct MemoryKv {
+        values: RefCell<HashMap<String, Vec<u8>>>,
+    }
+
+    impl KvBackend for MemoryKv {
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.values.borrow().get(key).cloned())
+        }
+
+        async fn put(&self, key: &str, value: Vec<u8>, _ttl: Option<u64>) -> Result<()> {
+            self.values.borrow_mut().insert(key.to_string(), value);
+            Ok(())
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            self.values.borrow_mut().remove(key);
+            Ok(())
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            let mut keys: Vec<_> = self
+                .values
+                .borrow()
+                .keys()
+                .filter(|x| x.starts_with(prefix))
+                .cloned()
+                .collect();
+            keys.sort();
+            Ok(keys)
+        }
+    }
+
+    #[tokio::test]
+    async fn json_round_trips_until_deleted() {
+        let kv = KvCache::new(MemoryKv::default());
+
+        kv.set("ids", vec![1u64, 2, 3], 60).await.unwrap();
+        assert_eq!(
+            kv.get_json::<Vec<u64>>("ids").await.unwrap(),
+            Some(vec![1, 2, 3])
+        );
+
+        kv.delete("ids").await.unwrap();
+        assert_eq!(kv.get_json::<Vec<u64>>("ids").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn missing_key_is_none() {
+        let kv = KvCache::new(MemoryKv::default());
+
+        assert_eq!(kv.get_text("nope").await.unwrap(), None);
+        assert_eq!(kv.get_compressed("nope").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn text_is_stored_as_is() {
+        let kv = KvCache::new(MemoryKv::default());
+
+        kv.set_text("name", 42, 60).await.unwrap();
+        assert_eq!(kv.get_text("name").await.unwrap().as_deref(), Some("42"));
+        assert_eq!(kv.kv.get("name").await.unwrap(), Some(b"42".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn compressed_round_trips() {
+        let kv = KvCache::new(MemoryKv::default());
+        let value = "https://example.com/video/1\n".repeat(100);
+
+        kv.set_compressed("links", &value, None).await.unwrap();
+        let stored = kv.kv.get("links").await.unwrap().unwrap();
+        assert!(stored.starts_with(&GZIP_MAGIC));
+        assert!(stored.len() < value.len());
+
+        assert_eq!(kv.get_compressed("links").await.unwrap(), Some(value));
+    }
+
+    #[tokio::test]
+    async fn get_compressed_reads_plain_text() {
+        let kv = KvCache::new(MemoryKv::default());
+
+        kv.set_text("links", "https://example.com/video/1", 60)
+            .await
+            .unwrap();
+        assert_eq!(
+            kv.get_compressed("links").await.unwrap().as_deref(),
+            Some("https://example.com/video/1")
+        );
     }
 }
@@ -1,11 +1,89 @@
 use hypertext::{Renderable, prelude::*, rsx};
 use itertools::Itertools;
-use worker::{Request, Response, Result, RouteContext};
+use worker::{KvStore, Request, Response, Result, RouteContext};
 
-pub async fn kv_list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+use crate::AppData;
+use crate::playlistformat::Delimiter;
+
+/// List every key name with the given prefix, following the list cursor
+pub async fn list_keys(kv: &KvStore, prefix: Option<&str>) -> Result<Vec<String>> {
+    let mut names = vec![];
+    let mut cursor: Option<String> = None;
+
+    loop {
+        let mut list = kv.list();
+        list = list.prefix(crate::kvprefix::key(prefix.unwrap_or("")));
+        if let Some(c) = cursor {
+            list = list.cursor(c);
+        }
+
+        let res = list.execute().await?;
+        names.extend(
+            res.keys
+                .into_iter()
+                .map(|x| crate::kvprefix::strip(&x.name).to_string()),
+        );
+
+        match res.cursor {
+            Some(c) if !res.list_complete => cursor = Some(c),
+            _ => break,
+        }
+    }
+
+    Ok(names)
+}
+
+/// One bounded slice of a key listing, see [`list_keys_page`]
+pub struct KeyPage {
+    pub names: Vec<String>,
+    /// Where the next slice starts, `None` once the listing is complete
+    pub cursor: Option<String>,
+}
+
+/// Like [`list_keys`], but stops after `limit` keys so a bulk operation
+/// stays within the worker's time and subrequest limits.
+///
+/// Continuation: when the returned `cursor` is `Some`, call again with it
+/// (and the same prefix) to get the following keys. Keep going until it's
+/// `None`. Bulk routes hand this cursor back to the caller as is.
+pub async fn list_keys_page(
+    kv: &KvStore,
+    prefix: Option<&str>,
+    mut cursor: Option<String>,
+    limit: usize,
+) -> Result<KeyPage> {
+    let mut names = vec![];
+
+    while names.len() < limit {
+        // KV lists at most 1000 keys at once
+        let mut list = kv.list().limit((limit - names.len()).min(1000) as u64);
+        list = list.prefix(crate::kvprefix::key(prefix.unwrap_or("")));
+        if let Some(c) = cursor.take() {
+            list = list.cursor(c);
+        }
+
+        let res = list.execute().await?;
+        names.extend(
+            res.keys
+                .into_iter()
+                .map(|x| crate::kvprefix::strip(&x.name).to_string()),
+        );
+
+        match res.cursor {
+            Some(c) if !res.list_complete => cursor = Some(c),
+            _ => break,
+        }
+    }
+
+    Ok(KeyPage { names, cursor })
+}
+
+/// Each delete is a subrequest, so keep well under the per-invocation limit
+pub const DEFAULT_BULK_KEY_LIMIT: usize = 500;
+
+pub async fn kv_list(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
     let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
-    let list = kv.list().execute().await?;
-    let names = list.keys.into_iter().map(|x| x.name).collect_vec();
+    let names = list_keys(&kv, None).await?;
 
     let as_html = req
         .headers()
@@ -13,6 +91,11 @@ pub async fn kv_list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
         .unwrap_or("".into())
         .contains("text/html");
 
+    let pagination = match crate::htmlgen::Pagination::from_url(&req.url()?) {
+        Ok(p) => p,
+        Err(e) => return Response::error(format!("Invalid pagination. {e}"), 400),
+    };
+
     if !as_html {
         Response::ok(names.join("\n"))
     } else {
@@ -22,13 +105,29 @@ pub async fn kv_list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
                     .into_iter()
                     .map(|x| crate::htmlgen::Nav::new(format!("kv/{x}"), &x))
                     .collect_vec(),
+                pagination,
             )
             .expect("Failed render template"),
         )
     }
 }
 
-pub async fn kv_get(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+/// Whether a missing key should read as empty instead of a 404, asked with
+/// `?default=empty` or an `X-KV-Default: empty` header
+fn missing_as_empty(req: &Request) -> Result<bool> {
+    let by_query = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "default" && v == "empty");
+    let by_header = req
+        .headers()
+        .get("X-KV-Default")?
+        .is_some_and(|x| x.eq_ignore_ascii_case("empty"));
+
+    Ok(by_query || by_header)
+}
+
+pub async fn kv_get(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
     let kvname = if let Some(n) = ctx.param("keyname") {
         n
     } else {
@@ -41,23 +140,119 @@ pub async fn kv_get(req: Request, ctx: RouteContext<()>) -> Result<Response> {
         .unwrap_or("".into())
         .contains("text/html");
 
+    let urls_only = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "as" && v == "urls");
+    let delimiter = match Delimiter::from_url(&req.url()?) {
+        Ok(d) => d,
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+    let since = match crate::links::parse_since(&req.url()?) {
+        Ok(s) => s,
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+
     let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
 
-    match kv.get(kvname).text().await? {
+    let value = match crate::kvcache::KvCache::new(kv)
+        .get_compressed(kvname)
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => return Response::error(format!("Failed reading KV. {e}"), 500),
+    };
+
+    match value {
         Some(s) => {
+            let s = match since.map(|x| crate::links::records_since(&s, x)) {
+                Some(Ok(filtered)) => filtered,
+                Some(Err(e)) => return Response::error(e.to_string(), 400),
+                None => s,
+            };
+            let s = if urls_only {
+                crate::links::project_urls(&s)
+            } else {
+                s
+            };
+
             if !as_html {
-                Response::ok(s)
+                Response::ok(delimiter.rejoin(&s))
             } else {
+                // A broken config shouldn't take the KV viewer down with it
+                let text_template = match ctx.data.config(&ctx.env).await {
+                    Ok(c) => c.text_template.clone(),
+                    Err(e) => {
+                        tracing::warn!("Rendering with the default template: {e}");
+                        None
+                    }
+                };
+                let stats = crate::htmlgen::TextStats::from_text(&s);
                 Response::from_html(
-                    crate::htmlgen::gen_plaintext(s.trim()).expect("Failed render template"),
+                    crate::htmlgen::gen_plaintext(s.trim(), Some(stats), text_template.as_deref())
+                        .expect("Failed render template"),
                 )
             }
         }
+        None if missing_as_empty(&req)? => Response::ok(""),
         None => Response::error("KV Empty", 404),
     }
 }
 
-pub async fn kv_new_get(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
+/// Same as `kv_get`, but always plain text, whatever the `Accept` header says
+pub async fn kv_get_raw(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    let kvname = if let Some(n) = ctx.param("keyname") {
+        n
+    } else {
+        return Response::error("KV not found", 404);
+    };
+
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+
+    let urls_only = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "as" && v == "urls");
+    let delimiter = match Delimiter::from_url(&req.url()?) {
+        Ok(d) => d,
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+    let since = match crate::links::parse_since(&req.url()?) {
+        Ok(s) => s,
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+
+    let value = match crate::kvcache::KvCache::new(kv)
+        .get_compressed(kvname)
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => return Response::error(format!("Failed reading KV. {e}"), 500),
+    };
+
+    match value {
+        Some(s) => {
+            let s = match since.map(|x| crate::links::records_since(&s, x)) {
+                Some(Ok(filtered)) => filtered,
+                Some(Err(e)) => return Response::error(e.to_string(), 400),
+                None => s,
+            };
+            let s = if urls_only {
+                crate::links::project_urls(&s)
+            } else {
+                s
+            };
+            let mut res = Response::ok(delimiter.rejoin(&s))?;
+            res.headers_mut()
+                .set("Content-Type", "text/plain; charset=utf-8")?;
+            Ok(res)
+        }
+        None if missing_as_empty(&req)? => Response::ok(""),
+        None => Response::error("KV Empty", 404),
+    }
+}
+
+pub async fn kv_new_get(_req: Request, _ctx: RouteContext<AppData>) -> Result<Response> {
     Response::from_html(
         rsx! {
         <!DOCTYPE html><html>
@@ -76,7 +271,7 @@ pub async fn kv_new_get(_req: Request, _ctx: RouteContext<()>) -> Result<Respons
     )
 }
 
-pub async fn kv_new_post(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+pub async fn kv_new_post(mut req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
     let body = req.text().await?;
     let form: std::collections::HashMap<String, String> = form_urlencoded::parse(body.as_bytes())
         .into_owned()
@@ -96,7 +291,64 @@ pub async fn kv_new_post(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
 
     let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
 
-    kv.put(kvname, kvvalue)?.execute().await?;
+    kv.put(&crate::kvprefix::key(kvname), kvvalue)?
+        .execute()
+        .await?;
 
     Response::ok("KV set")
 }
+
+/// Delete every key starting with `prefix`. Needs `confirm=true` alongside the
+/// prefix, either in the query or the form body.
+///
+/// At most `KV_BULK_KEY_LIMIT` keys go per request. If more are left, the
+/// response has a non-null `cursor`: send the same request again with
+/// `cursor` set to it, until the response's `cursor` is null.
+pub async fn kv_delete_prefix(mut req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let body = req.text().await?;
+    let params: std::collections::HashMap<String, String> = req
+        .url()?
+        .query_pairs()
+        .into_owned()
+        .chain(form_urlencoded::parse(body.as_bytes()).into_owned())
+        .collect();
+
+    let prefix = match params.get("prefix") {
+        Some(p) if !p.is_empty() => p,
+        _ => return Response::error("Missing or empty 'prefix' field", 400),
+    };
+
+    if params.get("confirm").map(String::as_str) != Some("true") {
+        return Response::error("Refusing to delete without 'confirm=true'", 400);
+    }
+
+    let config = match ctx.data.config(&ctx.env).await {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed loading config. {e}"), 500),
+    };
+
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+    let page = list_keys_page(
+        &kv,
+        Some(prefix),
+        params.get("cursor").filter(|x| !x.is_empty()).cloned(),
+        config.kv_bulk_key_limit,
+    )
+    .await?;
+
+    for name in &page.names {
+        kv.delete(&crate::kvprefix::key(name)).await?;
+    }
+
+    tracing::info!("Deleted {} keys with prefix {prefix}", page.names.len());
+
+    Response::from_json(&serde_json::json!({
+        "prefix": prefix,
+        "deleted": page.names.len(),
+        "cursor": page.cursor,
+    }))
+}
@@ -0,0 +1,36 @@
+use std::cell::OnceCell;
+
+thread_local! {
+    static PREFIX: OnceCell<String> = const { OnceCell::new() };
+}
+
+/// Read the key prefix from `KV_PREFIX`, empty when unset.
+///
+/// Call it at the start of an event, before any KV access. Later calls leave
+/// the prefix as it is.
+pub fn init(env: &worker::Env) {
+    let prefix = env
+        .var("KV_PREFIX")
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+
+    PREFIX.with(|x| {
+        x.get_or_init(|| prefix);
+    });
+}
+
+fn with_prefix<R>(f: impl FnOnce(&str) -> R) -> R {
+    PREFIX.with(|x| f(x.get().map(String::as_str).unwrap_or("")))
+}
+
+/// The stored name of `name`. Every KV read, write, delete and list prefix
+/// goes through this, so environments sharing a namespace never see each
+/// other's keys.
+pub fn key(name: &str) -> String {
+    with_prefix(|p| format!("{p}{name}"))
+}
+
+/// Undo [`key`] on a name coming back from a listing
+pub fn strip(stored: &str) -> &str {
+    with_prefix(|p| stored.strip_prefix(p).unwrap_or(stored))
+}
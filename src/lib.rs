@@ -1,17 +1,54 @@
+use std::rc::Rc;
 use std::str::FromStr;
 
 use worker::*;
 
+mod auth;
+mod compression;
+mod config;
 mod discord;
 mod fetcher;
 mod htmlgen;
 mod kvcache;
+mod kvprefix;
+mod links;
 mod playlist;
+mod playlistcache;
+mod playlistconfig;
+mod playlistformat;
+mod subrequests;
+mod timing;
+mod tools;
+mod urlguard;
 mod workercache;
 
+mod cachemanager;
+mod discordviewer;
+mod health;
 mod kvmanager;
+mod metrics;
 mod playlistviewer;
 
+/// Data handed to every route handler through `RouteContext::data`
+pub struct AppData {
+    /// The invocation's execution context, for `wait_until`
+    pub worker_ctx: Rc<worker::Context>,
+    /// Loaded on first use, see [`AppData::config`]
+    config: async_lock::OnceCell<config::AppConfig>,
+    /// This request's own, every fetch it makes goes through it
+    pub subrequests: subrequests::Subrequests,
+}
+
+impl AppData {
+    /// The worker's config, loaded the first time a handler asks for it.
+    /// Routes not needing it don't pay for the KV reads.
+    pub async fn config(&self, env: &Env) -> anyhow::Result<&config::AppConfig> {
+        self.config
+            .get_or_try_init(|| config::AppConfig::load(env))
+            .await
+    }
+}
+
 fn get_envvar(env: &Env) -> worker::wasm_bindgen::JsValue {
     env.var("ENV")
         .unwrap_or(worker::Var::from(worker::wasm_bindgen::JsValue::from_str(
@@ -21,22 +58,69 @@ fn get_envvar(env: &Env) -> worker::wasm_bindgen::JsValue {
         .clone()
 }
 
+/// Management and scraping routes crawlers are kept out of
+const NOINDEX_ROUTES: &[&str] = &["/kv", "/get", "/cron"];
+
+fn is_noindex_route(path: &str) -> bool {
+    NOINDEX_ROUTES
+        .iter()
+        .any(|x| path == *x || path.starts_with(&format!("{x}/")))
+}
+
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
     tracing_worker::init_tracing(if get_envvar(&env) == "production" {
         tracing::Level::INFO
     } else {
         tracing::Level::TRACE
     });
 
+    kvprefix::init(&env);
+
+    let ctx = Rc::new(ctx);
+    let metrics_kv = env.kv("VID_PLAYLIST_MANAGER_KV")?;
+    metrics::incr(format!(
+        "requests_total{{route=\"{}\"}}",
+        metrics::route_label(&req.path())
+    ));
+
+    // Authorized requests may hit guarded routes, never serve or store those from cache.
+    // Nor anything but GETs: the others change state, and the Cache API only stores GETs.
+    let cacheable = req.method() == Method::Get && req.headers().get("Authorization")?.is_none();
+
     let cache = Cache::default();
-    if let Some(cached) = cache.get(&req, false).await? {
+    if cacheable && let Some(cached) = cache.get(&req, false).await? {
         tracing::trace!("Cache HIT");
         return Ok(cached);
     }
 
-    let mut res = Router::new()
-        .get("/", |_, _| Response::error("", 404))
+    let subrequests = subrequests::Subrequests::from_env(&env);
+    let data = AppData {
+        worker_ctx: ctx.clone(),
+        config: async_lock::OnceCell::new(),
+        subrequests: subrequests.clone(),
+    };
+
+    let mut res = Router::with_data(data)
+        .get("/", |req, _| {
+            let as_html = req
+                .headers()
+                .get("Accept")?
+                .unwrap_or("".into())
+                .contains("text/html");
+
+            if as_html {
+                Response::from_html(htmlgen::gen_indexpage().expect("Failed render template"))
+            } else {
+                Response::ok(
+                    htmlgen::INDEX_ROUTES
+                        .iter()
+                        .map(|(href, text)| format!("{href}\t{text}"))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                )
+            }
+        })
         .get_async("/get", |req, ctx| async move {
             let url = req.url()?;
             let mut query_pairs = url.query_pairs();
@@ -46,20 +130,104 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 .map(|(_, value)| value.to_string());
 
             if let Some(u) = url {
-                match playlist::PlaylistFetcher::new().get(&u).await {
-                    Ok(x) => Response::ok(x),
+                let config = match ctx.data.config(&ctx.env).await {
+                    Ok(c) => c,
+                    Err(e) => return Response::error(format!("Failed loading config. {e}"), 500),
+                };
+                if let Err(e) = config.url_guard.check(&u) {
+                    return Response::error(format!("Refusing to fetch. {e}"), 400);
+                }
+
+                let as_json = req
+                    .headers()
+                    .get("Accept")?
+                    .unwrap_or("".into())
+                    .contains("application/json");
+
+                // Only what the config lists explicitly, e.g. a Referer a source checks
+                let mut forwarded = http::HeaderMap::new();
+                for name in &config.get_forward_headers {
+                    if let Some(value) = req.headers().get(name)?
+                        && let (Ok(name), Ok(value)) = (
+                            http::HeaderName::from_bytes(name.as_bytes()),
+                            http::HeaderValue::from_str(&value),
+                        )
+                    {
+                        forwarded.insert(name, value);
+                    }
+                }
+
+                match playlist::PlaylistFetcher::new(&ctx.data.subrequests)
+                    .with_headers(forwarded)
+                    .with_url_guard(config.url_guard.clone())
+                    .get_with_pages(&u)
+                    .await
+                {
+                    Ok((urls, pages)) if as_json => Response::from_json(&serde_json::json!({
+                        "source": u,
+                        "count": urls.len(),
+                        "pages": pages,
+                        "urls": urls,
+                    })),
+                    Ok((urls, _)) => Response::ok(urls.join("\n")),
+                    Err(e) if e.is::<fetcher::RedirectRefused>() => {
+                        Response::error(format!("Refusing to fetch. {e}"), 400)
+                    }
                     Err(e) => Response::error(format!("GET request failed. {e}"), 500),
                 }
             } else {
                 Response::error("url key empty", 400)
             }
         })
+        .get_async("/get/page", playlistviewer::playlist_page_inspect)
         .get_async("/kv", kvmanager::kv_list)
         .get_async("/kv/new", kvmanager::kv_new_get)
         .post_async("/kv/new", kvmanager::kv_new_post)
+        .post_async("/kv/delete", kvmanager::kv_delete_prefix)
         .get_async("/kv/:keyname", kvmanager::kv_get)
+        .get_async("/kv/:keyname/raw", kvmanager::kv_get_raw)
         .get_async("/playlist", playlistviewer::playlist_list)
+        .get_async("/playlist/sources", playlistviewer::playlist_sources)
+        .get_async("/playlist/all", playlistviewer::playlist_all)
         .get_async("/playlist/:name", playlistviewer::playlist_single)
+        .get_async("/playlist/:name/raw.txt", playlistviewer::playlist_raw)
+        .post_async("/playlist/:name/refresh", playlistviewer::playlist_refresh)
+        .get_async("/playlist/:name/diff", playlistviewer::playlist_diff)
+        .post_async("/playlist/preview", playlistviewer::playlist_preview)
+        .post_async(
+            "/playlist/config/validate",
+            playlistviewer::playlist_config_validate,
+        )
+        .get_async("/discord/hosts", discordviewer::discord_hosts)
+        .get_async("/discord/export", discordviewer::discord_export)
+        .get_async("/discord/:channel/recent", discordviewer::discord_recent)
+        .get_async("/discord/:channel/range", discordviewer::discord_range)
+        .post_async(
+            "/discord/:channel/watermark",
+            discordviewer::discord_watermark_set,
+        )
+        .delete_async(
+            "/discord/:channel/watermark",
+            discordviewer::discord_watermark_delete,
+        )
+        .get_async("/tools/snowflake/:id", tools::snowflake_to_time)
+        .get_async("/tools/time-to-snowflake", tools::time_to_snowflake)
+        .get_async("/cache/status", cachemanager::cache_status)
+        .post_async("/cache/purge", cachemanager::cache_purge)
+        .get("/robots.txt", |_, _| {
+            let disallows = NOINDEX_ROUTES
+                .iter()
+                .map(|x| format!("Disallow: {x}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            let mut res = Response::ok(format!("User-agent: *\n{disallows}\n"))?;
+            res.headers_mut()
+                .set("Content-Type", "text/plain; charset=utf-8")?;
+            Ok(res)
+        })
+        .get_async("/metrics", metrics::metrics_get)
+        .get_async("/health", health::health_get)
         .get("/test", |_, _| {
             tracing::trace!("Testing trace");
             tracing::debug!("Testing debug");
@@ -73,12 +241,38 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
         .run(req.clone().expect("Failed to clone request"), env)
         .await?;
 
-    res.headers_mut().set("Cache-Control", "max-age=60")?;
-    if let Ok(res) = res.cloned() {
-        cache.put(&req, res).await?;
+    // Fetches left running in `wait_until` aren't in this count
+    tracing::debug!("Sent {} subrequests", subrequests.sent());
+    metrics::incr_by("subrequests_total", subrequests.sent() as u64);
+
+    if is_noindex_route(&req.path()) {
+        res.headers_mut().set("X-Robots-Tag", "noindex")?;
     }
 
-    Ok(res)
+    // A handler may've opted its response out already
+    let no_store = res
+        .headers()
+        .get("Cache-Control")?
+        .is_some_and(|x| x.contains("no-store"));
+
+    if cacheable && !no_store {
+        res.headers_mut().set("Cache-Control", "max-age=60")?;
+        if let Ok(res) = res.cloned() {
+            cache.put(&req, res).await?;
+        }
+    } else {
+        res.headers_mut().set("Cache-Control", "no-store")?;
+    }
+
+    if metrics::flush_due() {
+        ctx.wait_until(async move {
+            if let Err(e) = metrics::flush(&metrics_kv).await {
+                tracing::warn!("Failed flushing metrics: {e}");
+            }
+        });
+    }
+
+    compression::finalize(&req, res)
 }
 
 #[event(scheduled)]
@@ -89,6 +283,8 @@ pub async fn cron_event(event: ScheduledEvent, env: Env, _ctx: ScheduleContext)
         tracing::Level::TRACE
     });
 
+    kvprefix::init(&env);
+
     // Do whatever you want here – e.g., call an API, clean up KV, etc.
     tracing::info!("Running scheduled task: {:?}", event.cron());
 
@@ -100,10 +296,25 @@ pub async fn cron_event(event: ScheduledEvent, env: Env, _ctx: ScheduleContext)
     tracing::debug!("cron description: {}", cron.describe());
     tracing::debug!("{crondiff} | {t_chrono} | {}", t as i64);
 
-    if let Err(e) = discord::mainfn(&env, crondiff).await {
+    metrics::incr("cron_runs_total");
+    let subrequests = subrequests::Subrequests::from_env(&env);
+    let res = match config::AppConfig::load(&env).await {
+        Ok(config) => discord::mainfn(&env, &config, &subrequests, crondiff).await,
+        Err(e) => Err(anyhow::anyhow!("Failed loading config. {e}")),
+    };
+    tracing::info!("Sent {} subrequests", subrequests.sent());
+    metrics::incr_by("subrequests_total", subrequests.sent() as u64);
+    if let Err(e) = res {
+        metrics::incr("cron_failures_total");
         tracing::error!("ERROR: {e}")
     }
 
+    if let Ok(kv) = env.kv("VID_PLAYLIST_MANAGER_KV")
+        && let Err(e) = metrics::flush(&kv).await
+    {
+        tracing::warn!("Failed flushing metrics: {e}");
+    }
+
     tracing::info!("Done running schedule task");
 
     // Ok(())
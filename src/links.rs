@@ -0,0 +1,542 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use worker::KvStore;
+
+const EXCLUDED_PATTERNS: &[&str] = &[
+    "cdn.",
+    "tenor.",
+    "redgifs.",
+    "discordapp.",
+    "redd.it",
+    "media.tumblr.",
+];
+
+const ALLOWED_SCHEMES: &[&str] = &["http", "https"];
+
+static FINDER: LazyLock<linkify::LinkFinder> = LazyLock::new(|| {
+    let mut finder = linkify::LinkFinder::new();
+    finder
+        .kinds(&[linkify::LinkKind::Url])
+        .url_must_have_scheme(true);
+    finder
+});
+/// Also matches `example.com/video`, see [`extract_links`]
+static SCHEMELESS_FINDER: LazyLock<linkify::LinkFinder> = LazyLock::new(|| {
+    let mut finder = linkify::LinkFinder::new();
+    finder
+        .kinds(&[linkify::LinkKind::Url])
+        .url_must_have_scheme(false);
+    finder
+});
+
+/// TLDs a schemeless match must end with, so `app.exe` or `v1.2.3` aren't taken for links
+const SCHEMELESS_TLDS: &[&str] = &[
+    "com", "net", "org", "io", "co", "me", "tv", "be", "ly", "gg", "xyz", "info", "dev", "app",
+    "uk", "us", "de", "fr", "jp", "ru", "nl", "to", "cc",
+];
+
+/// `https://` + the link if it's a bare domain ending with one of [`SCHEMELESS_TLDS`]
+fn normalize_schemeless(link: &str) -> Option<String> {
+    let host = link.split(['/', '?', '#', ':']).next().unwrap_or(link);
+    let (_, tld) = host.rsplit_once('.')?;
+
+    SCHEMELESS_TLDS
+        .iter()
+        .any(|x| tld.eq_ignore_ascii_case(x))
+        .then(|| format!("https://{link}"))
+}
+
+static EXCLUDER: LazyLock<aho_corasick::AhoCorasick> = LazyLock::new(|| {
+    aho_corasick::AhoCorasick::builder()
+        .ascii_case_insensitive(true)
+        .build(EXCLUDED_PATTERNS)
+        .expect("Failed to init filter")
+});
+
+/// Extract http(s) links from a message content, skipping emails and other schemes.
+///
+/// With `schemeless`, bare domains like `example.com/video` are picked up too
+/// and stored with `https://` in front.
+pub fn extract_links(content: &str, schemeless: bool) -> Vec<String> {
+    let finder = if schemeless {
+        &SCHEMELESS_FINDER
+    } else {
+        &FINDER
+    };
+
+    finder
+        .links(content)
+        .map(|x| x.as_str())
+        .filter_map(|x| match x.split_once("://") {
+            Some((scheme, _)) => ALLOWED_SCHEMES
+                .iter()
+                .any(|allowed| scheme.eq_ignore_ascii_case(allowed))
+                .then(|| x.to_string()),
+            None if schemeless => normalize_schemeless(x),
+            None => None,
+        })
+        .collect_vec()
+}
+
+#[derive(Clone, Debug)]
+enum AllowEntry {
+    Substring(String),
+    Regex(regex::Regex),
+}
+
+impl AllowEntry {
+    fn parse(line: &str) -> Result<Self> {
+        match line.strip_prefix('/').and_then(|x| x.strip_suffix('/')) {
+            Some(pattern) => {
+                Ok(Self::Regex(regex::Regex::new(pattern).map_err(|e| {
+                    anyhow::anyhow!("Invalid allow regex `{pattern}`: {e}")
+                })?))
+            }
+            None => Ok(Self::Substring(line.to_ascii_lowercase())),
+        }
+    }
+
+    fn is_match(&self, link: &str) -> bool {
+        match self {
+            Self::Substring(s) => link.to_ascii_lowercase().contains(s),
+            Self::Regex(re) => re.is_match(link),
+        }
+    }
+}
+
+/// Rewrites of stored links, from `config_link_rewrites`, one per line:
+///
+/// ```text
+/// origin.example.com -> cdn.mine.com
+/// /^https://old\.example\.com/v/(\d+)$/ -> https://mine.com/watch/$1
+/// ```
+///
+/// A plain entry swaps the host of links on exactly that host (any case),
+/// keeping the path and query. An entry wrapped in slashes is a regex, the
+/// right side a replacement template with `$1`-style groups. The first
+/// entry matching a link applies. Empty lines and `#` comments are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct LinkRewrites(Vec<Rewrite>);
+
+#[derive(Clone, Debug)]
+enum Rewrite {
+    Host {
+        from: String,
+        to: String,
+    },
+    Regex {
+        pattern: regex::Regex,
+        template: String,
+    },
+}
+
+impl std::str::FromStr for LinkRewrites {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(|line| {
+                let (from, to) = line
+                    .split_once("->")
+                    .map(|(from, to)| (from.trim(), to.trim()))
+                    .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+                    .ok_or_else(|| anyhow::anyhow!("Rewrite `{line}` isn't `from -> to`"))?;
+
+                match from.strip_prefix('/').and_then(|x| x.strip_suffix('/')) {
+                    Some(pattern) => Ok(Rewrite::Regex {
+                        pattern: regex::Regex::new(pattern).map_err(|e| {
+                            anyhow::anyhow!("Invalid rewrite regex `{pattern}`: {e}")
+                        })?,
+                        template: to.to_string(),
+                    }),
+                    None => Ok(Rewrite::Host {
+                        from: from.to_ascii_lowercase(),
+                        to: to.to_string(),
+                    }),
+                }
+            })
+            .try_collect()
+            .map(Self)
+    }
+}
+
+impl LinkRewrites {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `link` after the first matching rewrite, unchanged if none matches
+    pub fn apply(&self, link: &str) -> String {
+        self.0
+            .iter()
+            .find_map(|x| match x {
+                Rewrite::Host { from, to } => {
+                    let mut url = url::Url::parse(link).ok()?;
+                    if !url.host_str()?.eq_ignore_ascii_case(from) {
+                        return None;
+                    }
+                    url.set_host(Some(to)).ok()?;
+                    Some(url.to_string())
+                }
+                Rewrite::Regex { pattern, template } => pattern
+                    .is_match(link)
+                    .then(|| pattern.replace(link, template.as_str()).into_owned()),
+            })
+            .unwrap_or_else(|| link.to_string())
+    }
+}
+
+/// How `EXCLUDED_PATTERNS` are matched against a link
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExcludeMode {
+    /// Case-insensitive substring of the whole link. `redd.it` also matches
+    /// `notredd.it.evil.com`, and `cdn.` matches a path containing it.
+    #[default]
+    Substring,
+    /// Compared against the link's host only, on label boundaries:
+    /// - `redd.it` matches `redd.it` and `v.redd.it`, but not `fakeredd.it`
+    /// - patterns ending with a dot are leading labels, so `cdn.` matches
+    ///   `cdn.discordapp.com` and `media.cdn.example.com`, not `mycdn.net`
+    Host,
+}
+
+impl std::str::FromStr for ExcludeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "substring" => Ok(Self::Substring),
+            "host" => Ok(Self::Host),
+            other => Err(anyhow::anyhow!("Unknown exclude mode `{other}`")),
+        }
+    }
+}
+
+fn host_matches(host: &str, pattern: &str) -> bool {
+    if pattern.ends_with('.') {
+        host.starts_with(pattern) || host.contains(&format!(".{pattern}"))
+    } else {
+        host == pattern || host.ends_with(&format!(".{pattern}"))
+    }
+}
+
+/// Decides which extracted links are kept.
+///
+/// Checked in this order:
+/// 1. Links not matching `EXCLUDED_PATTERNS` are kept.
+/// 2. Links matching `EXCLUDED_PATTERNS` are dropped, *unless* they match an
+///    entry of the allowlist, in which case they're kept anyway.
+///
+/// So allow always beats exclude. The allowlist lives in the
+/// `config_link_allows` KV key, one entry per line. Plain entries are
+/// case-insensitive substrings, entries wrapped in slashes (`/^https://cdn\.example\.com/`)
+/// are regexes.
+///
+/// How exclusion patterns match is picked by `config_exclude_mode`, see [`ExcludeMode`].
+#[derive(Clone, Debug, Default)]
+pub struct LinkFilter {
+    allows: Vec<AllowEntry>,
+    mode: ExcludeMode,
+}
+
+impl LinkFilter {
+    pub fn new(allows: &[&str]) -> Result<Self> {
+        Ok(Self {
+            allows: allows
+                .iter()
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                .map(AllowEntry::parse)
+                .try_collect()?,
+            mode: ExcludeMode::default(),
+        })
+    }
+
+    pub fn with_mode(self, mode: ExcludeMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    pub async fn from_kv(kv: &KvStore) -> Result<Self> {
+        let allows = kv
+            .get(&crate::kvprefix::key("config_link_allows"))
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))?
+            .unwrap_or_default();
+
+        let mode = crate::config::get_parsed::<ExcludeMode>(kv, "config_exclude_mode")
+            .await?
+            .unwrap_or_default();
+
+        Ok(Self::new(&allows.lines().collect_vec())?.with_mode(mode))
+    }
+
+    fn matches_exclusion(&self, link: &str) -> bool {
+        match self.mode {
+            ExcludeMode::Substring => EXCLUDER.is_match(link),
+            ExcludeMode::Host => match url::Url::parse(link) {
+                Ok(url) => url.host_str().is_some_and(|host| {
+                    let host = host.to_ascii_lowercase();
+                    EXCLUDED_PATTERNS.iter().any(|x| host_matches(&host, x))
+                }),
+                // Not much of a host to look at, so fall back to the whole link
+                Err(_) => EXCLUDER.is_match(link),
+            },
+        }
+    }
+
+    pub fn is_allowed(&self, link: &str) -> bool {
+        self.allows.iter().any(|x| x.is_match(link))
+    }
+
+    /// Whether the link should be dropped. See [`LinkFilter`] for the precedence.
+    pub fn is_excluded(&self, link: &str) -> bool {
+        self.matches_exclusion(link) && !self.is_allowed(link)
+    }
+}
+
+/// Which repeats of a link are dropped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    /// Keep every link
+    #[default]
+    None,
+    /// Collapse a link repeated right after itself, like one pasted twice in a row
+    Adjacent,
+    /// Keep only the first occurrence of each link
+    Global,
+}
+
+impl std::str::FromStr for DedupMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "adjacent" => Ok(Self::Adjacent),
+            "global" => Ok(Self::Global),
+            other => Err(anyhow::anyhow!("Unknown dedup mode `{other}`")),
+        }
+    }
+}
+
+/// Running state of a [`DedupMode`], fed the links in order
+pub struct Deduper {
+    mode: DedupMode,
+    /// See [`Self::ignoring_scheme`]
+    ignore_scheme: bool,
+    seen: HashSet<String>,
+    last: Option<String>,
+}
+
+impl Deduper {
+    pub fn new(mode: DedupMode) -> Self {
+        Self {
+            mode,
+            ignore_scheme: false,
+            seen: HashSet::new(),
+            last: None,
+        }
+    }
+
+    /// Compare links as if `http://` were `https://`, so the two schemes of
+    /// one video count as a repeat. Only the comparison changes, the link
+    /// kept is passed on with its own scheme.
+    pub fn ignoring_scheme(self, ignore_scheme: bool) -> Self {
+        Self {
+            ignore_scheme,
+            ..self
+        }
+    }
+
+    /// Whether `link` survives, given the links passed in before it
+    pub fn keep(&mut self, link: &str) -> bool {
+        let upgraded;
+        let link = match link.get(..7) {
+            Some(scheme) if self.ignore_scheme && scheme.eq_ignore_ascii_case("http://") => {
+                upgraded = format!("https://{}", &link[7..]);
+                upgraded.as_str()
+            }
+            _ => link,
+        };
+
+        match self.mode {
+            DedupMode::None => true,
+            DedupMode::Adjacent => {
+                let repeated = self.last.as_deref() == Some(link);
+                self.last = Some(link.to_string());
+                !repeated
+            }
+            DedupMode::Global => self.seen.insert(link.to_string()),
+        }
+    }
+}
+
+/// A link along with where it came from, stored as one JSON line in the
+/// merged KV when `config_link_metadata` is enabled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkRecord {
+    pub url: String,
+    pub author: String,
+    pub channel: String,
+    /// RFC3339 timestamp of the source message
+    pub posted_at: String,
+}
+
+/// Project a merged value back to bare URLs, one per line.
+///
+/// JSON-lines records are reduced to their `url`, plain lines are kept as-is,
+/// so values mixing both (written before and after enabling metadata) work.
+pub fn project_urls(value: &str) -> String {
+    value.lines().map(line_url).join("\n")
+}
+
+/// The URL of one merged line, a JSON-lines record or a bare URL
+pub fn line_url(line: &str) -> String {
+    match serde_json::from_str::<LinkRecord>(line) {
+        Ok(record) => record.url,
+        Err(_) => line.to_string(),
+    }
+}
+
+/// `?since=` of a request, as RFC3339
+pub fn parse_since(url: &worker::Url) -> Result<Option<time::UtcDateTime>> {
+    let Some((_, v)) = url.query_pairs().find(|(k, _)| k == "since") else {
+        return Ok(None);
+    };
+
+    time::OffsetDateTime::parse(&v, &time::format_description::well_known::Rfc3339)
+        .map(|x| Some(x.to_utc()))
+        .map_err(|e| anyhow::anyhow!("`since` must be an RFC3339 timestamp: {e}"))
+}
+
+/// Keep the records of a merged value posted after `since`.
+///
+/// Only JSON-lines records carry a time. Plain lines in a mixed value were
+/// written before metadata was enabled, so they're older than any record and
+/// dropped. A value with no records at all can't be filtered and errors.
+pub fn records_since(value: &str, since: time::UtcDateTime) -> Result<String> {
+    let records = value
+        .lines()
+        .filter_map(|x| serde_json::from_str::<LinkRecord>(x).ok().map(|r| (x, r)))
+        .collect_vec();
+
+    if records.is_empty() && !value.trim().is_empty() {
+        anyhow::bail!(
+            "This value holds plain URLs without timestamps, enable `config_link_metadata` to filter by `since`"
+        );
+    }
+
+    Ok(records
+        .into_iter()
+        .filter(|(_, r)| {
+            time::OffsetDateTime::parse(
+                &r.posted_at,
+                &time::format_description::well_known::Rfc3339,
+            )
+            .is_ok_and(|t| t.to_utc() > since)
+        })
+        .map(|(line, _)| line)
+        .join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_links_skips_emails() {
+        let links = extract_links(
+            "mail me at someone@example.com or see https://example.com/video/1",
+            false,
+        );
+
+        assert_eq!(links, vec!["https://example.com/video/1"]);
+    }
+
+    #[test]
+    fn extract_links_skips_other_schemes() {
+        let links = extract_links(
+            "mailto:someone@example.com steam://run/440 http://example.com/a",
+            false,
+        );
+
+        assert_eq!(links, vec!["http://example.com/a"]);
+    }
+
+    #[test]
+    fn allow_entry_beats_exclusion() {
+        let link = "https://cdn.example.com/clip.mp4";
+        assert!(LinkFilter::default().is_excluded(link));
+
+        let filter = LinkFilter::new(&["cdn.example.com"]).unwrap();
+        assert!(!filter.is_excluded(link));
+        assert!(filter.is_excluded("https://cdn.other.com/clip.mp4"));
+
+        let filter = LinkFilter::new(&[r"/^https://cdn\.example\.com/.*\.mp4$/"]).unwrap();
+        assert!(!filter.is_excluded(link));
+    }
+
+    #[test]
+    fn ignore_scheme_keeps_first_with_its_scheme() {
+        let mut deduper = Deduper::new(DedupMode::Global).ignoring_scheme(true);
+        let kept = ["http://site/v/1", "https://site/v/1"]
+            .into_iter()
+            .filter(|x| deduper.keep(x))
+            .collect::<Vec<_>>();
+
+        assert_eq!(kept, vec!["http://site/v/1"]);
+    }
+
+    #[test]
+    fn host_rewrite_keeps_path_and_query() {
+        let rewrites: LinkRewrites = "origin.example.com -> cdn.mine.com".parse().unwrap();
+
+        assert_eq!(
+            rewrites.apply("https://Origin.Example.com/v/1?x=2"),
+            "https://cdn.mine.com/v/1?x=2"
+        );
+        // Exactly that host, not its subdomains
+        assert_eq!(
+            rewrites.apply("https://a.origin.example.com/v/1"),
+            "https://a.origin.example.com/v/1"
+        );
+    }
+
+    #[test]
+    fn regex_rewrite_fills_template() {
+        let rewrites: LinkRewrites = r"
+            # Old player links
+            /^https://old\.example\.com/v/(\d+)$/ -> https://mine.com/watch/$1
+        "
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            rewrites.apply("https://old.example.com/v/42"),
+            "https://mine.com/watch/42"
+        );
+        assert_eq!(
+            rewrites.apply("https://old.example.com/v/42/extra"),
+            "https://old.example.com/v/42/extra"
+        );
+    }
+
+    #[test]
+    fn malformed_rewrites_fail_to_parse() {
+        let err = "origin.example.com cdn.mine.com"
+            .parse::<LinkRewrites>()
+            .unwrap_err();
+        assert!(err.to_string().contains("isn't `from -> to`"));
+
+        let err = "/([/ -> x".parse::<LinkRewrites>().unwrap_err();
+        assert!(err.to_string().contains("Invalid rewrite regex"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use anyhow::Result;
+use itertools::Itertools;
+use web_time::Instant;
+use worker::{KvStore, Request, Response, RouteContext};
+
+use crate::AppData;
+
+const METRICS_KEY: &str = "metrics_counters";
+
+/// Requests flush at most this often, see [`flush_due`]
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Top-level routes counted under their own label. Any other path counts as
+/// `other`, so requests for random paths can't add counters without end.
+const ROUTES: &[&str] = &[
+    "/",
+    "/get",
+    "/kv",
+    "/playlist",
+    "/discord",
+    "/tools",
+    "/cache",
+    "/metrics",
+    "/health",
+    "/robots.txt",
+];
+
+thread_local! {
+    /// Increments not yet written to KV
+    static PENDING: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    /// When this isolate last flushed, or started counting
+    static LAST_FLUSH: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The `route` label of a request for `path`
+pub fn route_label(path: &str) -> &'static str {
+    let first = path.split('/').nth(1).unwrap_or_default();
+
+    ROUTES
+        .iter()
+        .find(|x| x.strip_prefix('/') == Some(first))
+        .copied()
+        .unwrap_or("other")
+}
+
+/// Increment a counter by one. Names follow Prometheus conventions,
+/// labels included, e.g. `requests_total{route="/kv"}`.
+pub fn incr(name: impl Into<String>) {
+    incr_by(name, 1);
+}
+
+pub fn incr_by(name: impl Into<String>, by: u64) {
+    PENDING.with_borrow_mut(|x| *x.entry(name.into()).or_default() += by);
+}
+
+pub async fn load(kv: &KvStore) -> Result<BTreeMap<String, u64>> {
+    Ok(kv
+        .get(&crate::kvprefix::key(METRICS_KEY))
+        .json::<BTreeMap<String, u64>>()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get kv: {e:?}"))?
+        .unwrap_or_default())
+}
+
+/// Whether a request should flush the pending increments, true once per
+/// [`FLUSH_INTERVAL`] so not every request pays for a KV read and write.
+///
+/// Increments an isolate holds when it's evicted are lost, at most an
+/// interval's worth. The cron job flushes on every run regardless.
+pub fn flush_due() -> bool {
+    LAST_FLUSH.with(|x| {
+        let now = Instant::now();
+        let due = x
+            .get()
+            .is_some_and(|last| now.duration_since(last) >= FLUSH_INTERVAL);
+
+        if due || x.get().is_none() {
+            x.set(Some(now));
+        }
+        due
+    })
+}
+
+/// Add the pending increments to the counters stored in KV.
+///
+/// This is a plain read-modify-write: concurrent flushes can overwrite each
+/// other and lose a few increments. Good enough for a dashboard.
+pub async fn flush(kv: &KvStore) -> Result<()> {
+    let pending = PENDING.with_borrow_mut(std::mem::take);
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let mut counters = load(kv).await?;
+    for (k, v) in pending {
+        *counters.entry(k).or_default() += v;
+    }
+
+    kv.put(&crate::kvprefix::key(METRICS_KEY), &counters)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize KV value: {e:?}"))?
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to put kv: {e:?}"))
+}
+
+fn to_prometheus(counters: &BTreeMap<String, u64>) -> String {
+    counters
+        .iter()
+        .chunk_by(|(k, _)| k.split('{').next().unwrap_or(k))
+        .into_iter()
+        .map(|(name, group)| {
+            std::iter::once(format!("# TYPE {name} counter"))
+                .chain(group.map(|(k, v)| format!("{k} {v}")))
+                .join("\n")
+        })
+        .join("\n")
+        + "\n"
+}
+
+pub async fn metrics_get(req: Request, ctx: RouteContext<AppData>) -> worker::Result<Response> {
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+
+    let as_prometheus = req
+        .url()?
+        .query_pairs()
+        .any(|(k, v)| k == "format" && v == "prometheus")
+        || req
+            .headers()
+            .get("Accept")?
+            .unwrap_or("".into())
+            .contains("text/plain");
+
+    let counters = match load(&kv).await {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed loading metrics. {e}"), 500),
+    };
+
+    if as_prometheus {
+        let mut res = Response::ok(to_prometheus(&counters))?;
+        res.headers_mut()
+            .set("Content-Type", "text/plain; version=0.0.4")?;
+        Ok(res)
+    } else {
+        Response::from_json(&counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_label_is_a_known_route_or_other() {
+        assert_eq!(route_label("/"), "/");
+        assert_eq!(route_label("/playlist/foo/raw.txt"), "/playlist");
+        assert_eq!(route_label("/kv"), "/kv");
+        assert_eq!(route_label("/wp-login.php"), "other");
+        assert_eq!(route_label("/playlistx"), "other");
+    }
+}
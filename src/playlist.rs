@@ -1,8 +1,14 @@
+use std::{collections::HashSet, sync::LazyLock, time::Duration};
+
 use anyhow::Result;
+use futures::{Stream, StreamExt, TryStreamExt, future, stream};
 use itertools::Itertools;
 use scraper::Selector;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::links::{DedupMode, Deduper};
+
 fn get_page_links(document: &scraper::html::Html) -> Vec<String> {
     let selector = Selector::parse("a").unwrap();
 
@@ -13,26 +19,123 @@ fn get_page_links(document: &scraper::html::Html) -> Vec<String> {
         .collect()
 }
 
+static REL_NEXT: LazyLock<Selector> =
+    LazyLock::new(|| Selector::parse(r#"a[rel~="next"], link[rel~="next"]"#).unwrap());
+
+/// Link text a "next page" anchor is recognized by, once arrows are trimmed off
+const NEXT_TEXTS: [&str; 3] = ["next", "next page", "older"];
+
+static LAST_NUMBER: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"(\d+)\D*$").unwrap());
+
+/// Parse a CSS selector from the config
+pub fn parse_selector(selector: &str) -> Result<Selector> {
+    Selector::parse(selector).map_err(|e| anyhow::anyhow!("Invalid selector `{selector}`: {e}"))
+}
+
+/// A video link, along with the thumbnail shown for it if the page has one
+#[derive(Serialize, Debug, Clone)]
+pub struct VideoEntry {
+    pub url: String,
+    pub thumb: Option<String>,
+}
+
 /// Extracts all links starting with a given prefix, removes query parameters
-fn get_video_links(document: &scraper::html::Html, starts_with: &str) -> Vec<String> {
-    let selector = Selector::parse("a").unwrap();
+/// and, unless `keep_fragment` is set, the fragment.
+/// The `src` of the first `<img>` inside each link, resolved against `page_url`,
+/// is kept as its thumbnail.
+///
+/// With a custom `selector`, every matched element's `href` is taken instead,
+/// resolved against `page_url`, whatever it starts with.
+fn get_video_links(
+    document: &scraper::html::Html,
+    page_url: &str,
+    starts_with: &str,
+    selector: Option<&Selector>,
+    keep_fragment: bool,
+) -> Vec<VideoEntry> {
+    let default_selector = Selector::parse("a").unwrap();
+    let img_selector = Selector::parse("img").unwrap();
+    let base = Url::parse(page_url).ok();
 
     document
-        .select(&selector)
-        .filter_map(|element| element.value().attr("href").map(|href| href.to_string()))
-        .filter(|href| href.starts_with(starts_with))
-        .filter_map(|href| {
+        .select(selector.unwrap_or(&default_selector))
+        .filter_map(|element| {
+            let href = element.value().attr("href")?;
+            if selector.is_none() && !href.starts_with(starts_with) {
+                return None;
+            }
+
             // Parse URL and strip query parameters
-            if let Ok(mut parsed) = Url::parse(&href) {
-                parsed.set_query(None);
-                Some(parsed.to_string())
-            } else {
-                None
+            let mut parsed = match &base {
+                Some(base) => base.join(href).ok()?,
+                None => Url::parse(href).ok()?,
+            };
+            parsed.set_query(None);
+            if !keep_fragment {
+                parsed.set_fragment(None);
             }
+
+            let thumb = element
+                .select(&img_selector)
+                .next()
+                .and_then(|img| img.value().attr("src"))
+                .map(
+                    |src| match base.as_ref().and_then(|base| base.join(src).ok()) {
+                        Some(resolved) => resolved.to_string(),
+                        None => src.to_string(),
+                    },
+                );
+
+            Some(VideoEntry {
+                url: normalize_path_encoding(parsed).to_string(),
+                thumb,
+            })
         })
         .collect()
 }
 
+/// Decode then re-encode each path segment, so `Caf%C3%A9`, `Caf%c3%a9` and
+/// `Café` all end up as the same link. Encoded slashes stay inside their segment.
+/// Paths that don't decode to UTF-8 are left alone.
+fn normalize_path_encoding(mut url: Url) -> Url {
+    let segments: Option<Vec<String>> = url.path_segments().and_then(|segments| {
+        segments
+            .map(|x| urlencoding::decode(x).map(|x| x.into_owned()).ok())
+            .collect()
+    });
+
+    if let Some(segments) = segments
+        && let Ok(mut path) = url.path_segments_mut()
+    {
+        path.clear().extend(segments);
+    }
+
+    url
+}
+
+/// Whether two page URLs are the same page, trailing slash aside
+fn same_page(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/') == b.trim_end_matches('/')
+}
+
+/// Prefix a link must start with to count as a video of the playlist at `url`
+pub fn video_baseurl(url: &str) -> String {
+    get_baseurl(url) + "/video/"
+}
+
+/// `link` as a path on the origin of the playlist at `url`, e.g.
+/// `https://example.com/video/1` becomes `/video/1`. Links on another origin
+/// stay absolute, so `/video/1` of two hosts never turn into the same line.
+pub fn relative_link(link: &str, url: &str) -> String {
+    match (Url::parse(link), Url::parse(&get_baseurl(url))) {
+        (Ok(parsed), Ok(base)) if parsed.origin() == base.origin() => {
+            parsed[url::Position::BeforePath..].to_string()
+        }
+        _ => link.to_string(),
+    }
+}
+
 fn get_baseurl(rawurl: &str) -> String {
     // Ensure the input has a scheme
     let mut url_input = rawurl.to_string();
@@ -48,68 +151,820 @@ fn get_baseurl(rawurl: &str) -> String {
     format!("{}://{}", parsed.scheme(), parsed.host_str().unwrap_or(""))
 }
 
+/// How the pages after the first are picked
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pagination {
+    /// Only the page numbers linked from page 1, so a pager with gaps
+    /// (`1 2 10`) doesn't send requests for pages that don't exist. Links
+    /// without gaps give the same pages as `range`.
+    Advertised,
+    /// Every page from 2 up to the highest one linked, for pagers leaving
+    /// out the middle pages like `1 2 3 … 50`
+    #[default]
+    Range,
+    /// Follow the "next page" link of each page in turn, for sites without
+    /// numbered pages. One page at a time, bounded by the page cap and
+    /// [`NEXT_PAGE_BUDGET`].
+    Next,
+}
+
+/// Pages followed through "next" links by default, page 1 included
+const DEFAULT_MAX_NEXT_PAGES: u32 = 50;
+/// Time given to following "next" links, after which the pages so far are kept
+const NEXT_PAGE_BUDGET: web_time::Duration = web_time::Duration::from_secs(20);
+
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
+/// Max pages fetched concurrently per playlist
+pub const PAGE_CONCURRENCY: usize = 8;
+/// How long fetched pages stay in the worker Cache by default
+const DEFAULT_PAGE_CACHE_TTL: usize = 60 * 5;
+/// How long past that a page is kept to be revalidated instead of refetched
+const PAGE_REVALIDATE_WINDOW: usize = 60 * 60 * 24;
+
+/// How the pages of a playlist are numbered and which of them are fetched.
+/// Worked out from the HTML of page 1 alone, apart from any fetching.
+#[derive(Clone, Default)]
+struct Pager {
+    /// See [`PlaylistFetcher::with_page_url_template`]
+    template: Option<String>,
+    /// Page selector of [`PlaylistFetcher::with_selectors`]
+    selector: Option<Selector>,
+    /// See [`PlaylistFetcher::with_pagination`]
+    pagination: Pagination,
+}
+
+impl Pager {
+    fn page_url(&self, url: &str, page: u32) -> String {
+        match &self.template {
+            Some(template) => template
+                .replace("{base}", url.trim_end_matches('/'))
+                .replace("{page}", &page.to_string()),
+            None => format!("{url}page{page}.html"),
+        }
+    }
+
+    /// Page numbers advertised by the links of `doc`, which was fetched from `url`
+    fn page_numbers(&self, doc: &scraper::html::Html, url: &str) -> Result<Vec<u32>> {
+        let (template, selector) = match (&self.template, &self.selector) {
+            (None, None) => {
+                return get_page_links(doc)
+                    .into_iter()
+                    .unique()
+                    .map(|x| {
+                        x[4..x.len() - 5]
+                            .parse::<u32>()
+                            .map_err(|e| anyhow::anyhow!("Failed to parse {x}: {e}"))
+                    })
+                    .try_collect();
+            }
+            (template, selector) => (template, selector),
+        };
+
+        let default_selector = Selector::parse("a").unwrap();
+        let base = Url::parse(url)?;
+        let hrefs = doc
+            .select(selector.as_ref().unwrap_or(&default_selector))
+            .filter_map(|x| x.value().attr("href"))
+            .filter_map(|href| base.join(href).ok())
+            // A pager linking the current page isn't pointing at another one,
+            // whatever number its URL happens to hold
+            .filter(|href| !same_page(href.as_str(), url));
+
+        let Some(template) = template else {
+            // Selected by hand, so the last number of the link is taken as its page
+            return Ok(hrefs
+                .filter_map(|href| {
+                    LAST_NUMBER
+                        .captures(href.as_str())
+                        .and_then(|x| x[1].parse::<u32>().ok())
+                })
+                .collect());
+        };
+
+        // The template with `{page}` capturing digits, matched against absolute hrefs
+        let pattern = regex::escape(&template.replace("{base}", url.trim_end_matches('/')))
+            .replace(r"\{page\}", r"(\d+)");
+        let pattern = regex::Regex::new(&format!("^{pattern}$"))?;
+
+        Ok(hrefs
+            .filter_map(|href| {
+                pattern
+                    .captures(href.as_str())
+                    .and_then(|x| x[1].parse::<u32>().ok())
+            })
+            .collect())
+    }
+
+    /// Pages after the first to fetch, out of the ones linked from page 1 at `url`
+    fn pages_to_fetch(&self, url: &str, mut advertised: Vec<u32>) -> Vec<u32> {
+        advertised.retain(|x| *x > 1);
+        advertised.sort_unstable();
+        advertised.dedup();
+
+        let Some(&maxpage) = advertised.last() else {
+            return Vec::new();
+        };
+
+        let sequential = advertised.len() == maxpage as usize - 1;
+        let pages = if sequential || self.pagination == Pagination::Range {
+            (2..=maxpage).collect()
+        } else {
+            tracing::debug!("Pagination has gaps, fetching only pages {advertised:?}");
+            advertised
+        };
+
+        // Page 1 is already fetched, under whatever number a template gives it
+        pages
+            .into_iter()
+            .filter(|x| !same_page(&self.page_url(url, *x), url))
+            .collect()
+    }
+}
+
+#[derive(Clone)]
 pub struct PlaylistFetcher {
     fetcher: crate::fetcher::Client,
+    /// Page numbering and which pages are fetched
+    pager: Pager,
+    /// See [`Self::with_selectors`]
+    video_selector: Option<Selector>,
+    /// See [`Self::with_empty_page_retries`]
+    empty_page_retries: u32,
+    /// See [`Self::with_dedup`]
+    dedup: DedupMode,
+    /// See [`Self::with_dedup_ignore_scheme`]
+    dedup_ignore_scheme: bool,
+    /// See [`Self::with_next_selector`]
+    next_selector: Option<Selector>,
+    /// See [`Self::with_max_pages`]
+    max_pages: u32,
+    /// See [`Self::with_keep_fragment`]
+    keep_fragment: bool,
+    /// See [`Self::with_detect_charset`]
+    detect_charset: bool,
 }
 
 impl PlaylistFetcher {
-    pub fn new() -> Self {
+    pub fn new(subrequests: &crate::subrequests::Subrequests) -> Self {
+        Self {
+            fetcher: crate::fetcher::Client::new("")
+                .with_cache_ttl(DEFAULT_PAGE_CACHE_TTL)
+                .with_revalidate_window(PAGE_REVALIDATE_WINDOW)
+                // A site that's down fails the remaining pages fast instead of
+                // each one waiting out its backoff
+                .with_circuit_breaker(crate::fetcher::BreakerConfig::default())
+                .with_subrequests(subrequests),
+            pager: Pager::default(),
+            video_selector: None,
+            empty_page_retries: 0,
+            dedup: DedupMode::None,
+            dedup_ignore_scheme: false,
+            next_selector: None,
+            max_pages: DEFAULT_MAX_NEXT_PAGES,
+            keep_fragment: false,
+            detect_charset: false,
+        }
+    }
+
+    /// Fetcher with the options set on the playlist source applied
+    pub fn for_source(
+        source: &crate::playlistconfig::PlaylistSource,
+        subrequests: &crate::subrequests::Subrequests,
+    ) -> Self {
+        let fetcher = Self::new(subrequests);
+
+        let fetcher = if source.headers.is_empty() {
+            fetcher
+        } else {
+            fetcher.with_headers(source.headers.clone())
+        };
+
+        let fetcher = match source.page_cache_ttl {
+            Some(ttl) => fetcher.with_page_cache_ttl(ttl),
+            None => fetcher,
+        };
+
+        let fetcher = if source.breaker_threshold.is_some() || source.breaker_cooldown.is_some() {
+            let default = crate::fetcher::BreakerConfig::default();
+            fetcher.with_circuit_breaker(crate::fetcher::BreakerConfig {
+                threshold: source.breaker_threshold.unwrap_or(default.threshold),
+                cooldown: source
+                    .breaker_cooldown
+                    .map_or(default.cooldown, Duration::from_secs),
+                ..default
+            })
+        } else {
+            fetcher
+        };
+
+        let fetcher = match &source.cache_directives {
+            Some(directives) => fetcher.with_cache_directives(directives),
+            None => fetcher,
+        };
+
+        let fetcher = match source.max_body_bytes {
+            Some(max) => fetcher.with_max_body_bytes(max),
+            None => fetcher,
+        };
+
+        let fetcher = match source.connect_timeout {
+            Some(secs) => fetcher.with_connect_timeout(Duration::from_secs(secs)),
+            None => fetcher,
+        };
+
+        let fetcher = match source.read_timeout {
+            Some(secs) => fetcher.with_read_timeout(Duration::from_secs(secs)),
+            None => fetcher,
+        };
+
+        let fetcher = match &source.page_url_template {
+            Some(template) => fetcher.with_page_url_template(template),
+            None => fetcher,
+        };
+
+        let fetcher = match source.max_pages {
+            Some(max) => fetcher.with_max_pages(max),
+            None => fetcher,
+        };
+
+        fetcher
+            .with_empty_page_retries(source.empty_page_retries)
+            .with_dedup(source.dedup)
+            .with_dedup_ignore_scheme(source.dedup_ignore_scheme)
+            .with_pagination(source.pagination)
+            .with_keep_fragment(source.keep_fragment)
+            .with_detect_charset(source.detect_charset)
+            .with_selectors(
+                source.video_selector.as_deref(),
+                source.page_selector.as_deref(),
+            )
+            .and_then(|x| x.with_next_selector(source.next_selector.as_deref()))
+            .expect("Selectors are validated when loading the config")
+    }
+
+    /// Seconds the raw page HTML stays in the worker Cache.
+    ///
+    /// Pages are cached under their own URL, so they can't collide with the
+    /// Discord API responses cached by the same `fetcher::Client`.
+    pub fn with_page_cache_ttl(self, secs: usize) -> Self {
+        Self {
+            fetcher: self.fetcher.with_cache_ttl(secs),
+            ..self
+        }
+    }
+
+    /// Check every redirect a page sends against `guard` before following it,
+    /// for URLs a caller supplied, see [`crate::fetcher::Client::with_url_guard`]
+    pub fn with_url_guard(self, guard: crate::urlguard::UrlGuard) -> Self {
+        Self {
+            fetcher: self.fetcher.with_url_guard(guard),
+            ..self
+        }
+    }
+
+    /// When the circuit of a failing site opens and how long it stays open,
+    /// see [`crate::fetcher::Client::with_circuit_breaker`]
+    pub fn with_circuit_breaker(self, config: crate::fetcher::BreakerConfig) -> Self {
+        Self {
+            fetcher: self.fetcher.with_circuit_breaker(config),
+            ..self
+        }
+    }
+
+    /// See [`crate::fetcher::Client::with_cache_directives`]
+    pub fn with_cache_directives(self, directives: impl ToString) -> Self {
+        Self {
+            fetcher: self.fetcher.with_cache_directives(directives),
+            ..self
+        }
+    }
+
+    /// Largest page body read, see [`crate::fetcher::Client::with_max_body_bytes`]
+    pub fn with_max_body_bytes(self, max_body_bytes: usize) -> Self {
+        Self {
+            fetcher: self.fetcher.with_max_body_bytes(max_body_bytes),
+            ..self
+        }
+    }
+
+    /// See [`crate::fetcher::Client::with_connect_timeout`]
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self {
+        Self {
+            fetcher: self.fetcher.with_connect_timeout(timeout),
+            ..self
+        }
+    }
+
+    /// See [`crate::fetcher::Client::with_read_timeout`]
+    pub fn with_read_timeout(self, timeout: Duration) -> Self {
+        Self {
+            fetcher: self.fetcher.with_read_timeout(timeout),
+            ..self
+        }
+    }
+
+    /// Headers sent with every page request, e.g. a session cookie
+    pub fn with_headers(self, headers: http::HeaderMap) -> Self {
+        Self {
+            fetcher: self.fetcher.with_headers(headers),
+            ..self
+        }
+    }
+
+    /// Build page URLs from a template instead of `{url}page{N}.html`.
+    ///
+    /// `{base}` is replaced by the playlist URL without its trailing slash and
+    /// `{page}` by the page number, e.g. `{base}/list/{page}/` or `{base}?pg={page}`.
+    /// The last page is then read from the on-page links matching the template.
+    pub fn with_page_url_template(self, template: impl ToString) -> Self {
+        Self {
+            pager: Pager {
+                template: Some(template.to_string()),
+                ..self.pager
+            },
+            ..self
+        }
+    }
+
+    /// CSS selectors picking the video and pagination links, replacing the
+    /// built-in `a` with prefix/`page<N>.html` matching. `None` keeps the default.
+    pub fn with_selectors(self, video: Option<&str>, page: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            video_selector: video.map(parse_selector).transpose()?,
+            pager: Pager {
+                selector: page.map(parse_selector).transpose()?,
+                ..self.pager
+            },
+            ..self
+        })
+    }
+
+    /// Refetch a page up to `retries` times when it has no video links.
+    ///
+    /// On top of the HTTP-level retries, which only see the status: a site
+    /// can answer 200 with an error page. The cached copy is dropped before
+    /// each refetch, so it actually reaches the site.
+    pub fn with_empty_page_retries(self, retries: u32) -> Self {
+        Self {
+            empty_page_retries: retries,
+            ..self
+        }
+    }
+
+    /// Drop repeated video links across the whole playlist, see [`DedupMode`]
+    pub fn with_dedup(self, dedup: DedupMode) -> Self {
+        Self { dedup, ..self }
+    }
+
+    /// Have dedup count the `http://` and `https://` of one link as a repeat,
+    /// see [`Deduper::ignoring_scheme`]
+    pub fn with_dedup_ignore_scheme(self, dedup_ignore_scheme: bool) -> Self {
+        Self {
+            dedup_ignore_scheme,
+            ..self
+        }
+    }
+
+    fn deduper(&self) -> Deduper {
+        Deduper::new(self.dedup).ignoring_scheme(self.dedup_ignore_scheme)
+    }
+
+    /// How the pages after the first are picked, see [`Pagination`]
+    pub fn with_pagination(self, pagination: Pagination) -> Self {
+        Self {
+            pager: Pager {
+                pagination,
+                ..self.pager
+            },
+            ..self
+        }
+    }
+
+    /// CSS selector of the "next page" link followed by [`Pagination::Next`].
+    /// `None` looks for a `rel="next"` link, then for an anchor reading "next".
+    pub fn with_next_selector(self, selector: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            next_selector: selector.map(parse_selector).transpose()?,
+            ..self
+        })
+    }
+
+    /// Most pages followed by [`Pagination::Next`], page 1 included
+    pub fn with_max_pages(self, max_pages: u32) -> Self {
+        Self { max_pages, ..self }
+    }
+
+    /// Keep the `#fragment` of video links. They're dropped by default so
+    /// `/video/1#t=30` and `/video/1` count as the same video, but sites
+    /// routing on the hash need it.
+    pub fn with_keep_fragment(self, keep_fragment: bool) -> Self {
+        Self {
+            keep_fragment,
+            ..self
+        }
+    }
+
+    /// Read pages in the charset they declare instead of requiring UTF-8,
+    /// see [`crate::fetcher::Client::get_text_lossy`]. Off, a page that
+    /// isn't valid UTF-8 fails the scrape.
+    pub fn with_detect_charset(self, detect_charset: bool) -> Self {
         Self {
-            fetcher: crate::fetcher::Client::new("").with_cache_ttl(60 * 5),
+            detect_charset,
+            ..self
         }
     }
+
+    /// Absolute URL of the "next page" link of `doc`, fetched from `page_url`
+    fn next_page_url(&self, doc: &scraper::html::Html, page_url: &str) -> Option<String> {
+        let anchor = Selector::parse("a").unwrap();
+        let href = match &self.next_selector {
+            Some(selector) => doc.select(selector).find_map(|x| x.value().attr("href")),
+            None => doc
+                .select(&REL_NEXT)
+                .find_map(|x| x.value().attr("href"))
+                .or_else(|| {
+                    doc.select(&anchor)
+                        .find(|x| {
+                            let text = x.text().collect::<String>();
+                            let text = text
+                                .trim_matches(|c: char| c.is_whitespace() || "›»→>".contains(c))
+                                .to_lowercase();
+                            NEXT_TEXTS.contains(&text.as_str())
+                        })
+                        .and_then(|x| x.value().attr("href"))
+                }),
+        }?;
+
+        Url::parse(page_url)
+            .and_then(|base| base.join(href))
+            .ok()
+            .map(String::from)
+    }
+
     async fn get_text_cached(&self, endpoint: &str) -> Result<String> {
-        self.fetcher.get_text(endpoint).await
+        if self.detect_charset {
+            self.fetcher.get_text_lossy(endpoint).await
+        } else {
+            self.fetcher.get_text(endpoint).await
+        }
     }
 
-    pub async fn get(&self, url: &str) -> Result<String> {
-        let vid_baseurl = get_baseurl(url) + "/video/";
+    /// Fetch `endpoint` and its video links, refetching while it has none,
+    /// see [`Self::with_empty_page_retries`]
+    #[tracing::instrument(skip(self, vid_baseurl), fields(elapsed_ms = tracing::field::Empty))]
+    async fn fetch_page(
+        &self,
+        endpoint: &str,
+        vid_baseurl: &str,
+    ) -> Result<(String, Vec<VideoEntry>)> {
+        let _timer = crate::timing::SpanTimer::start();
+        let mut attempt = 0;
+
+        loop {
+            let html = self.get_text_cached(endpoint).await?;
+            let links = get_video_links(
+                &scraper::Html::parse_document(&html),
+                endpoint,
+                vid_baseurl,
+                self.video_selector.as_ref(),
+                self.keep_fragment,
+            );
+
+            if !links.is_empty() || attempt >= self.empty_page_retries {
+                if attempt > 0 && !links.is_empty() {
+                    tracing::info!(
+                        "Refetch {attempt} of {endpoint} recovered {} links",
+                        links.len()
+                    );
+                }
+                return Ok((html, links));
+            }
 
-        let res = self.get_text_cached(url).await?;
+            attempt += 1;
+            tracing::warn!(
+                "No video links on {endpoint}, refetching ({attempt}/{})",
+                self.empty_page_retries
+            );
+            self.fetcher.evict(endpoint).await?;
+        }
+    }
+
+    /// Fetch page 1, returning its video links and the numbers of the pages
+    /// to fetch after it
+    async fn get_first_page(
+        &self,
+        url: &str,
+        vid_baseurl: &str,
+    ) -> Result<(Vec<VideoEntry>, Vec<u32>)> {
+        let (res, vidlinks) = self.fetch_page(url, vid_baseurl).await?;
         let doc = scraper::Html::parse_document(&res);
-        let pagelinks = get_page_links(&doc).into_iter().dedup().collect_vec();
-        let vidlinks = get_video_links(&doc, &vid_baseurl);
-
-        let pagenum: Vec<u32> = pagelinks
-            .iter()
-            .map(|x| {
-                x[4..x.len() - 5]
-                    .parse::<u32>()
-                    .map_err(|e| anyhow::anyhow!("Failed to parse {x}: {e}"))
-            })
-            .try_collect()?;
+        let pagenum = self.pager.page_numbers(&doc, url)?;
 
-        let maxpage = pagenum.into_iter().max().unwrap_or(1);
-        let sem = std::sync::Arc::new(async_lock::Semaphore::new(8));
+        Ok((vidlinks, self.pager.pages_to_fetch(url, pagenum)))
+    }
 
-        let pagelinks = (2..(maxpage + 1))
-            .map(|x| {
-                let endpoint = format!("{url}page{}.html", x);
-                let vid_baseurl = vid_baseurl.clone();
-                let sem = sem.clone();
+    async fn get_page(&self, url: &str, vid_baseurl: &str, page: u32) -> Result<Vec<VideoEntry>> {
+        tracing::trace!("Fetching page {page}");
 
-                async move {
-                    let _permit = sem.acquire().await;
-                    tracing::trace!("Fetching page {x}");
+        let endpoint = self.pager.page_url(url, page);
+        let (_, links) = self.fetch_page(&endpoint, vid_baseurl).await?;
 
-                    let res = self.get_text_cached(&endpoint).await?;
-                    let doc = scraper::Html::parse_document(&res);
-                    let links = get_video_links(&doc, &vid_baseurl);
+        Ok(links)
+    }
+
+    /// Fetch a single page on its own, returning its raw HTML and video links
+    pub async fn inspect_page(&self, url: &str, page: u32) -> Result<(String, Vec<VideoEntry>)> {
+        let endpoint = if page <= 1 {
+            url.to_string()
+        } else {
+            self.pager.page_url(url, page)
+        };
+
+        let html = self.get_text_cached(&endpoint).await?;
+        let doc = scraper::Html::parse_document(&html);
+        let links = get_video_links(
+            &doc,
+            &endpoint,
+            &video_baseurl(url),
+            self.video_selector.as_ref(),
+            self.keep_fragment,
+        );
+
+        Ok((html, links))
+    }
+
+    /// Video links of each page, page 1 first and the rest in page order
+    fn pages(&self, url: &str) -> impl Stream<Item = Result<Vec<VideoEntry>>> + 'static {
+        if self.pager.pagination == Pagination::Next {
+            return self.follow_next(url).left_stream();
+        }
+
+        let this = self.clone();
+        let url = url.to_string();
+
+        let pages = async move {
+            let vid_baseurl = video_baseurl(&url);
+            let (vidlinks, pages) = this.get_first_page(&url, &vid_baseurl).await?;
+
+            let rest = stream::iter(pages)
+                .map(move |page| {
+                    let (this, url, vid_baseurl) = (this.clone(), url.clone(), vid_baseurl.clone());
+                    async move { this.get_page(&url, &vid_baseurl, page).await }
+                })
+                .buffered(PAGE_CONCURRENCY);
+
+            anyhow::Ok(stream::once(future::ready(anyhow::Ok(vidlinks))).chain(rest))
+        };
+
+        stream::once(pages).try_flatten().right_stream()
+    }
 
-                    anyhow::Ok(links)
+    /// Video links of page 1 and the pages after it reached through "next"
+    /// links, stopping at the last page, at `max_pages` or once
+    /// [`NEXT_PAGE_BUDGET`] is used up
+    fn follow_next(&self, url: &str) -> impl Stream<Item = Result<Vec<VideoEntry>>> + 'static {
+        struct State {
+            next: Option<String>,
+            seen: HashSet<String>,
+            started: web_time::Instant,
+        }
+
+        let this = self.clone();
+        let vid_baseurl = video_baseurl(url);
+        let state = State {
+            next: Some(url.to_string()),
+            seen: HashSet::new(),
+            started: web_time::Instant::now(),
+        };
+
+        stream::try_unfold(state, move |mut state| {
+            let (this, vid_baseurl) = (this.clone(), vid_baseurl.clone());
+
+            async move {
+                let Some(endpoint) = state.next.take() else {
+                    return Ok(None);
+                };
+
+                if state.seen.len() as u32 >= this.max_pages {
+                    tracing::warn!(
+                        "Stopped following next links at the {} page cap",
+                        this.max_pages
+                    );
+                    return Ok(None);
+                }
+                if state.started.elapsed() >= NEXT_PAGE_BUDGET {
+                    tracing::warn!(
+                        "Stopped following next links after {} pages, out of time",
+                        state.seen.len()
+                    );
+                    return Ok(None);
                 }
-            })
-            .collect_vec();
 
-        let links = futures::future::try_join_all(pagelinks).await?;
+                tracing::trace!("Fetching page {}", state.seen.len() + 1);
+                let (html, links) = this.fetch_page(&endpoint, &vid_baseurl).await?;
+                state.seen.insert(endpoint.clone());
+
+                state.next = this
+                    .next_page_url(&scraper::Html::parse_document(&html), &endpoint)
+                    // A next link pointing back to a page already seen would loop
+                    .filter(|x| !state.seen.contains(x));
+
+                anyhow::Ok(Some((links, state)))
+            }
+        })
+    }
+
+    /// Yield the video links of every page as they resolve.
+    ///
+    /// Pages are fetched concurrently, but links always come out in page order:
+    /// page 1 first, then the following pages in sequence.
+    pub fn stream(&self, url: &str) -> impl Stream<Item = Result<String>> + 'static {
+        self.stream_entries(url).map_ok(|x| x.url)
+    }
 
-        let links = std::iter::once(vidlinks)
-            .chain(links)
+    /// Same as [`Self::stream`], with each link's thumbnail
+    pub fn stream_entries(&self, url: &str) -> impl Stream<Item = Result<VideoEntry>> + 'static {
+        let mut dedup = self.deduper();
+
+        self.pages(url)
+            .map_ok(|links| stream::iter(links.into_iter().map(anyhow::Ok)))
+            .try_flatten()
+            .try_filter(move |x| future::ready(dedup.keep(&x.url)))
+    }
+
+    /// Collect every video link of the playlist, in page order
+    #[tracing::instrument(skip(self), fields(elapsed_ms = tracing::field::Empty))]
+    pub async fn get_links(&self, url: &str) -> Result<Vec<String>> {
+        let _timer = crate::timing::SpanTimer::start();
+        self.stream(url).try_collect().await
+    }
+
+    /// Collect every video link of the playlist with its thumbnail, in page order
+    #[tracing::instrument(skip(self), fields(elapsed_ms = tracing::field::Empty))]
+    pub async fn get_entries(&self, url: &str) -> Result<Vec<VideoEntry>> {
+        let _timer = crate::timing::SpanTimer::start();
+        self.stream_entries(url).try_collect().await
+    }
+
+    /// Collect every video link, along with the number of pages fetched for them
+    #[tracing::instrument(skip(self), fields(elapsed_ms = tracing::field::Empty))]
+    pub async fn get_with_pages(&self, url: &str) -> Result<(Vec<String>, u32)> {
+        let _timer = crate::timing::SpanTimer::start();
+        let pages: Vec<Vec<VideoEntry>> = self.pages(url).try_collect().await?;
+        let pagecount = pages.len() as u32;
+
+        let mut dedup = self.deduper();
+        let urls = pages
+            .into_iter()
             .flatten()
-            .collect_vec();
+            .map(|x| x.url)
+            .filter(|x| dedup.keep(x))
+            .collect();
+
+        Ok((urls, pagecount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "https://example.com/list/";
+    const VIDEOS: &str = "https://example.com/video/";
+
+    fn doc(html: &str) -> scraper::Html {
+        scraper::Html::parse_document(html)
+    }
+
+    fn urls(entries: Vec<VideoEntry>) -> Vec<String> {
+        entries.into_iter().map(|x| x.url).collect()
+    }
+
+    #[test]
+    fn encoded_paths_collapse_to_one_form() {
+        let html = doc(r#"
+            <a href="https://example.com/video/Caf%C3%A9">1</a>
+            <a href="https://example.com/video/Caf%c3%a9">2</a>
+            <a href="https://example.com/video/Café">3</a>
+        "#);
+
+        assert_eq!(
+            urls(get_video_links(&html, BASE, VIDEOS, None, false)),
+            vec!["https://example.com/video/Caf%C3%A9"; 3]
+        );
+    }
+
+    #[test]
+    fn fragments_dedup_unless_kept() {
+        let html = doc(r#"
+            <a href="https://example.com/video/1#t=30">1</a>
+            <a href="https://example.com/video/1">2</a>
+        "#);
+        let dedup = |entries: Vec<VideoEntry>| {
+            let mut deduper = Deduper::new(DedupMode::Global);
+            urls(entries)
+                .into_iter()
+                .filter(|x| deduper.keep(x))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            dedup(get_video_links(&html, BASE, VIDEOS, None, false)),
+            vec!["https://example.com/video/1"]
+        );
+        assert_eq!(
+            dedup(get_video_links(&html, BASE, VIDEOS, None, true)),
+            vec![
+                "https://example.com/video/1#t=30",
+                "https://example.com/video/1"
+            ]
+        );
+    }
+
+    #[test]
+    fn thumbs_resolve_against_the_page() {
+        let html = doc(r#"
+            <a href="https://example.com/video/1"><img src="/thumbs/1.jpg"></a>
+            <a href="https://example.com/video/2"><img src="//cdn.example.net/2.jpg"></a>
+            <a href="https://example.com/video/3"><img src="https://img.example.org/3.jpg"></a>
+            <a href="https://example.com/video/4">4</a>
+        "#);
+
+        let thumbs = get_video_links(&html, BASE, VIDEOS, None, false)
+            .into_iter()
+            .map(|x| x.thumb)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            thumbs,
+            vec![
+                Some("https://example.com/thumbs/1.jpg".to_string()),
+                Some("https://cdn.example.net/2.jpg".to_string()),
+                Some("https://img.example.org/3.jpg".to_string()),
+                None,
+            ]
+        );
+    }
+
+    fn pages(pager: &Pager, html: &str, url: &str) -> Vec<u32> {
+        let advertised = pager.page_numbers(&doc(html), url).unwrap();
+        pager.pages_to_fetch(url, advertised)
+    }
+
+    #[test]
+    fn single_page_fetches_nothing_more() {
+        let html = r#"<a href="https://example.com/video/1">1</a>"#;
+
+        assert_eq!(pages(&Pager::default(), html, BASE), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn gaps_are_filled_unless_advertised_only() {
+        let html = r#"
+            <a href="page2.html">2</a>
+            <a href="page3.html">3</a>
+            <a href="page5.html">5</a>
+            <a href="page2.html">next</a>
+        "#;
+
+        assert_eq!(pages(&Pager::default(), html, BASE), vec![2, 3, 4, 5]);
+
+        let advertised = Pager {
+            pagination: Pagination::Advertised,
+            ..Default::default()
+        };
+        assert_eq!(pages(&advertised, html, BASE), vec![2, 3, 5]);
+    }
+
+    #[test]
+    fn link_to_current_page_isnt_a_page_number() {
+        // Without skipping the self link, its year would read as page 2020
+        let url = "https://example.com/list/2020/";
+        let html = r#"
+            <nav>
+                <a href="/list/2020/">1</a>
+                <a href="/list/2020/page2.html">2</a>
+            </nav>
+        "#;
+        let pager = Pager {
+            selector: Some(parse_selector("nav a").unwrap()),
+            ..Default::default()
+        };
+
+        assert_eq!(pager.page_numbers(&doc(html), url).unwrap(), vec![2]);
+        assert_eq!(pages(&pager, html, url), vec![2]);
+    }
+
+    #[test]
+    fn page_scraped_from_isnt_fetched_again() {
+        // The playlist URL is page 2 of this template, already fetched
+        let pager = Pager {
+            template: Some("https://example.com/list/{page}".to_string()),
+            ..Default::default()
+        };
+        let url = "https://example.com/list/2";
 
-        Ok(links.join("\n"))
+        assert_eq!(pager.pages_to_fetch(url, vec![1, 2, 3]), vec![3]);
     }
 }
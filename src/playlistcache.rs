@@ -0,0 +1,72 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use worker::KvStore;
+
+use crate::playlistconfig::PlaylistSource;
+
+/// How long result entries are kept in KV regardless of the source's
+/// `cache_ttl`, so a stale copy is still around to be served while refreshing.
+const RESULT_RETENTION: u64 = 604_800;
+
+/// A scraped playlist, as stored under `playlist_result_{name}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedPlaylist {
+    /// Unix timestamp (seconds) of the scrape
+    pub fetched_at: i64,
+    pub urls: Vec<String>,
+}
+
+impl CachedPlaylist {
+    pub fn is_fresh(&self, ttl: u64) -> bool {
+        let age = time::UtcDateTime::now().unix_timestamp() - self.fetched_at;
+        age < ttl as i64
+    }
+}
+
+#[derive(Clone)]
+pub struct PlaylistResultCache {
+    kv: crate::kvcache::KvCache,
+}
+
+impl PlaylistResultCache {
+    pub fn new(kv: KvStore) -> Self {
+        Self {
+            kv: crate::kvcache::KvCache::new(kv),
+        }
+    }
+
+    fn key(name: &str) -> String {
+        format!("playlist_result_{name}")
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Option<CachedPlaylist>> {
+        self.kv.get_json(Self::key(name)).await
+    }
+
+    pub async fn put(&self, name: &str, urls: Vec<String>) -> Result<CachedPlaylist> {
+        let entry = CachedPlaylist {
+            fetched_at: time::UtcDateTime::now().unix_timestamp(),
+            urls,
+        };
+        self.kv
+            .set(Self::key(name), &entry, RESULT_RETENTION)
+            .await?;
+
+        Ok(entry)
+    }
+
+    /// Re-scrape the source and store the result under its name
+    pub async fn refresh(
+        &self,
+        source: &PlaylistSource,
+        subrequests: &crate::subrequests::Subrequests,
+    ) -> Result<CachedPlaylist> {
+        let urls = crate::playlist::PlaylistFetcher::for_source(source, subrequests)
+            .get_links(&source.url)
+            .await?;
+        // Keep serving the last good result rather than caching a broken scrape
+        source.check_min_videos(urls.len())?;
+
+        self.put(&source.name, urls).await
+    }
+}
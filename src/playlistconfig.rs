@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use worker::KvStore;
+
+/// Typed form of the `config_playlist` KV key
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlaylistConfig {
+    pub playlist_sources: Vec<PlaylistSource>,
+}
+
+/// Names taken by fixed `/playlist/*` routes, a source named so couldn't be reached
+const RESERVED_NAMES: &[&str] = &["all", "sources"];
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlaylistSource {
+    pub name: String,
+    pub url: String,
+
+    /// Default ordering of the playlist. `None` means source order.
+    ///
+    /// The `?reversed` query doesn't set the ordering, it *inverts* this
+    /// default when present: a source with `reversed = true` is served in
+    /// source order when requested with `?reversed`.
+    pub reversed: Option<bool>,
+
+    /// Seconds a scraped result stays fresh in its `playlist_result_{name}`
+    /// KV entry. `None` disables result caching for this source.
+    pub cache_ttl: Option<u64>,
+
+    /// Once the cached result is stale, serve it anyway and re-scrape in the
+    /// background instead of making the request wait. Needs `cache_ttl`.
+    #[serde(default)]
+    pub stale_while_revalidate: bool,
+
+    /// Seconds the raw page HTML stays in the worker Cache. Defaults to 5 minutes.
+    pub page_cache_ttl: Option<usize>,
+
+    /// Failed page requests in a row that stop the scrape from sending more
+    /// to the site. Defaults to 5.
+    pub breaker_threshold: Option<u32>,
+
+    /// Seconds the scrape then refuses requests to the site. Defaults to 30.
+    pub breaker_cooldown: Option<u64>,
+
+    /// `Cache-Control` directives the cached page HTML is stored with, ahead
+    /// of its `max-age`. Defaults to `private=Set-Cookie`, empty sends none.
+    pub cache_directives: Option<String>,
+
+    /// Largest page body read, in bytes. Bigger pages fail the scrape.
+    /// Defaults to 16 MiB.
+    pub max_body_bytes: Option<usize>,
+
+    /// Seconds to wait for a page's response headers before retrying it.
+    /// No limit by default.
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds a page's body may stall before the scrape fails, without
+    /// retrying. No limit by default.
+    pub read_timeout: Option<u64>,
+
+    /// Page URL with `{base}` and `{page}` placeholders, for sites not using
+    /// `page<N>.html`. See `PlaylistFetcher::with_page_url_template`.
+    pub page_url_template: Option<String>,
+
+    /// CSS selectors for the video and pagination links, when the default
+    /// `a` matching doesn't fit the site. Checked when the config is loaded.
+    pub video_selector: Option<String>,
+    pub page_selector: Option<String>,
+
+    /// Request headers for gated sources, as header name -> name of the worker
+    /// secret holding the value, e.g. `{ Cookie = "GALLERY_COOKIE" }`.
+    /// The values never sit in the config itself.
+    #[serde(default)]
+    pub secret_headers: BTreeMap<String, String>,
+
+    /// `secret_headers` with the secrets looked up, see [`PlaylistConfig::resolve_secrets`]
+    #[serde(skip)]
+    pub headers: http::HeaderMap,
+
+    /// Scrapes yielding fewer links are treated as broken (layout change,
+    /// blocked) and fail instead of serving a near-empty playlist. 0 disables it.
+    #[serde(default)]
+    pub min_videos: usize,
+
+    /// Refetch a page whose HTML had no video links up to that many times,
+    /// for sites that now and then serve an empty error page. 0 disables it.
+    #[serde(default)]
+    pub empty_page_retries: u32,
+
+    /// Which repeats of a video link are dropped: `none` (the default),
+    /// `adjacent` or `global`
+    #[serde(default)]
+    pub dedup: crate::links::DedupMode,
+
+    /// Have dedup treat `http://` and `https://` of a link as the same video.
+    /// Links are still served with the scheme they were found with.
+    #[serde(default)]
+    pub dedup_ignore_scheme: bool,
+
+    /// Which pages after the first are fetched: `range` (the default) every
+    /// page up to the highest linked, for pagers hiding the middle pages,
+    /// `advertised` only the ones linked from page 1, for pagers with gaps,
+    /// and `next` follows "next page" links one page at a time
+    #[serde(default)]
+    pub pagination: crate::playlist::Pagination,
+
+    /// CSS selector of the "next page" link for `pagination = "next"`.
+    /// Defaults to a `rel="next"` link, then an anchor reading "next".
+    pub next_selector: Option<String>,
+
+    /// Most pages followed with `pagination = "next"`. Defaults to 50.
+    pub max_pages: Option<u32>,
+
+    /// Keep the `#fragment` of video links, for sites routing on it. They're
+    /// stripped by default so the same video with a timestamp isn't a new link.
+    #[serde(default)]
+    pub keep_fragment: bool,
+
+    /// Decode pages in the charset named by their `Content-Type` or
+    /// `<meta charset>`, for sites serving Latin-1, Shift-JIS and the like.
+    /// Pages naming none are read as UTF-8 with bad bytes replaced.
+    #[serde(default)]
+    pub detect_charset: bool,
+}
+
+impl PlaylistSource {
+    /// Resolve the final ordering given whether `?reversed` was passed
+    pub fn is_reversed(&self, reversed_query: bool) -> bool {
+        self.reversed.unwrap_or(false) ^ reversed_query
+    }
+
+    pub fn check_min_videos(&self, count: usize) -> Result<()> {
+        if count < self.min_videos {
+            return Err(anyhow!(
+                "Source `{}` yielded {count} videos, expected at least {}",
+                self.name,
+                self.min_videos
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl PlaylistConfig {
+    /// Parse the config, rejecting duplicate playlist names
+    pub fn from_toml(s: &str) -> Result<Self> {
+        let config: Self =
+            toml::from_str(s).map_err(|e| anyhow!("Failed to parse playlist config: {e}"))?;
+        config.check_unique_names()?;
+        config.check_selectors()?;
+
+        Ok(config)
+    }
+
+    /// Two sources sharing a name would make one of them unreachable
+    fn check_unique_names(&self) -> Result<()> {
+        let mut seen = HashSet::new();
+
+        match self
+            .playlist_sources
+            .iter()
+            .find(|x| !seen.insert(x.name.as_str()))
+        {
+            Some(dup) => Err(anyhow!("Duplicate playlist name `{}`", dup.name)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_selectors(&self) -> Result<()> {
+        for source in &self.playlist_sources {
+            for selector in [
+                &source.video_selector,
+                &source.page_selector,
+                &source.next_selector,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                crate::playlist::parse_selector(selector)
+                    .map_err(|e| anyhow!("In source `{}`: {e}", source.name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stricter checks than loading does, for configs about to be deployed
+    pub fn validate(&self) -> Result<()> {
+        self.check_unique_names()?;
+
+        for source in &self.playlist_sources {
+            if RESERVED_NAMES.contains(&source.name.as_str()) {
+                return Err(anyhow!(
+                    "`{}` is reserved, pick another playlist name",
+                    source.name
+                ));
+            }
+
+            url::Url::parse(&source.url)
+                .map_err(|e| anyhow!("Invalid url `{}` for `{}`: {e}", source.url, source.name))?;
+
+            if let Some(template) = &source.page_url_template
+                && !template.contains("{page}")
+            {
+                return Err(anyhow!(
+                    "`page_url_template` of `{}` has no `{{page}}` placeholder",
+                    source.name
+                ));
+            }
+
+            if source.breaker_threshold == Some(0) {
+                return Err(anyhow!(
+                    "`breaker_threshold` of `{}` must be at least 1",
+                    source.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The stored config, or `None` when `config_playlist` is missing or blank
+    pub async fn from_kv_opt(kv: &KvStore) -> Result<Option<Self>> {
+        let tomlstr = kv
+            .get(&crate::kvprefix::key("config_playlist"))
+            .text()
+            .await
+            .map_err(|e| anyhow!("Failed to get kv: {e:?}"))?;
+
+        Self::from_toml_opt(tomlstr.as_deref())
+    }
+
+    /// [`Self::from_toml`], `None` for a missing or blank value. Blank TOML
+    /// parses to an empty table, which would only fail later as having no sources.
+    fn from_toml_opt(s: Option<&str>) -> Result<Option<Self>> {
+        match s.map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(s) => Self::from_toml(s).map(Some),
+        }
+    }
+
+    /// Fill every source's `headers` from the secrets its `secret_headers` names
+    pub fn resolve_secrets(mut self, env: &worker::Env) -> Result<Self> {
+        for source in &mut self.playlist_sources {
+            for (header, secret) in &source.secret_headers {
+                let value = env.secret(secret).map_err(|e| {
+                    anyhow!("Secret `{secret}` for `{}` not available: {e}", source.name)
+                })?;
+
+                source.headers.insert(
+                    http::HeaderName::from_bytes(header.as_bytes())?,
+                    http::HeaderValue::from_str(&value.to_string())?,
+                );
+            }
+        }
+
+        Ok(self)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PlaylistSource> {
+        self.playlist_sources.iter().find(|x| x.name == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.playlist_sources
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let err = PlaylistConfig::from_toml(
+            r#"
+            [[playlist_sources]]
+            name = "clips"
+            url = "https://example.com/a/"
+
+            [[playlist_sources]]
+            name = "clips"
+            url = "https://example.com/b/"
+            "#,
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("Duplicate playlist name `clips`"));
+    }
+
+    #[test]
+    fn missing_or_blank_config_is_none() {
+        assert!(PlaylistConfig::from_toml_opt(None).unwrap().is_none());
+        assert!(PlaylistConfig::from_toml_opt(Some("")).unwrap().is_none());
+        assert!(
+            PlaylistConfig::from_toml_opt(Some("  \n"))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn zero_breaker_threshold_is_rejected() {
+        let config = PlaylistConfig::from_toml(
+            r#"
+            [[playlist_sources]]
+            name = "clips"
+            url = "https://example.com/a/"
+            breaker_threshold = 0
+            breaker_cooldown = 120
+            "#,
+        )
+        .unwrap();
+
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("`breaker_threshold` of `clips`"));
+    }
+
+    #[test]
+    fn all_is_rejected_as_a_name() {
+        let config = PlaylistConfig::from_toml(
+            r#"
+            [[playlist_sources]]
+            name = "all"
+            url = "https://example.com/a/"
+            "#,
+        )
+        .unwrap();
+
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("`all` is reserved"));
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+use itertools::Itertools;
+
+/// Output formats `playlist_single` can render the collected URLs into
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Newline-joined URLs (or the HTML text page, depending on `Accept`)
+    #[default]
+    Text,
+    /// XSPF XML playlist, as understood by VLC and friends
+    Xspf,
+    /// Zip of Windows `.url` internet shortcuts, one per video
+    UrlsZip,
+}
+
+/// What plain text link lists are joined with, from `?delimiter=`.
+///
+/// Only the output changes: lists are always stored newline separated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Delimiter {
+    #[default]
+    Lf,
+    Crlf,
+    Comma,
+    Space,
+}
+
+impl FromStr for Delimiter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "lf" => Ok(Self::Lf),
+            "crlf" => Ok(Self::Crlf),
+            "comma" => Ok(Self::Comma),
+            "space" => Ok(Self::Space),
+            other => Err(anyhow!(
+                "Unknown delimiter `{other}`, expected lf, crlf, comma or space"
+            )),
+        }
+    }
+}
+
+impl Delimiter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::Crlf => "\r\n",
+            Self::Comma => ",",
+            Self::Space => " ",
+        }
+    }
+
+    /// Parse `?delimiter=` off a request URL, `Lf` when it's missing
+    pub fn from_url(url: &worker::Url) -> Result<Self> {
+        match url.query_pairs().find(|(k, _)| k == "delimiter") {
+            Some((_, v)) => v.parse(),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Re-join a stored newline separated list with this delimiter
+    pub fn rejoin(&self, value: &str) -> String {
+        match self {
+            Self::Lf => value.to_string(),
+            _ => value.lines().join(self.as_str()),
+        }
+    }
+}
+
+/// Links past this are left out of `urls-zip` archives
+const MAX_ZIP_ENTRIES: usize = 2000;
+
+impl FromStr for PlaylistFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "txt" => Ok(Self::Text),
+            "xspf" => Ok(Self::Xspf),
+            "urls-zip" => Ok(Self::UrlsZip),
+            other => Err(anyhow!("Unknown playlist format `{other}`")),
+        }
+    }
+}
+
+impl PlaylistFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Text => "text/plain; charset=utf-8",
+            Self::Xspf => "application/xspf+xml",
+            Self::UrlsZip => "application/zip",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Xspf => "xspf",
+            Self::UrlsZip => "zip",
+        }
+    }
+
+    /// Render the collected URLs of the playlist `name` into this format
+    pub fn render(&self, name: &str, urls: &[String]) -> Result<Vec<u8>> {
+        match self {
+            Self::Text => Ok(urls.join("\n").into_bytes()),
+            Self::Xspf => Ok(render_xspf(name, urls).into_bytes()),
+            Self::UrlsZip => render_urls_zip(urls),
+        }
+    }
+}
+
+/// File name of the shortcut: position in the playlist, then the last path segment
+fn shortcut_name(index: usize, url: &str) -> String {
+    let slug = url
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .take(80)
+        .collect::<String>();
+
+    if slug.is_empty() {
+        format!("{:05}.url", index + 1)
+    } else {
+        format!("{:05} {slug}.url", index + 1)
+    }
+}
+
+fn render_urls_zip(urls: &[String]) -> Result<Vec<u8>> {
+    if urls.len() > MAX_ZIP_ENTRIES {
+        tracing::warn!(
+            "Playlist has {} links, only the first {MAX_ZIP_ENTRIES} go in the zip",
+            urls.len()
+        );
+    }
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    // Shortcuts are tiny, compressing them isn't worth it
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+    for (i, url) in urls.iter().take(MAX_ZIP_ENTRIES).enumerate() {
+        zip.start_file(shortcut_name(i, url), options)?;
+        write!(zip, "[InternetShortcut]\r\nURL={url}\r\n")?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn render_xspf(name: &str, urls: &[String]) -> String {
+    let tracks = urls
+        .iter()
+        .map(|x| format!("    <track><location>{}</location></track>", xml_escape(x)))
+        .join("\n");
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>{}</title>
+  <trackList>
+{tracks}
+  </trackList>
+</playlist>
+"#,
+        xml_escape(name)
+    )
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
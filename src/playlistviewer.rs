@@ -1,51 +1,64 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 
+use futures::TryStreamExt;
+use hypertext::{Renderable, prelude::*, rsx};
 use itertools::Itertools;
 use worker::{Request, Response, Result, RouteContext};
 
-pub async fn playlist_list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+use crate::AppData;
+use crate::playlistcache::PlaylistResultCache;
+use crate::playlistconfig::{PlaylistConfig, PlaylistSource};
+use crate::playlistformat::{Delimiter, PlaylistFormat};
 
+pub async fn playlist_list(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
     let as_html = req
         .headers()
         .get("Accept")?
         .unwrap_or("".into())
         .contains("text/html");
 
-    let tomlstr = kv.get("config_playlist").text().await?.unwrap_or("".into());
-    let tomlval = toml::from_str::<toml::Value>(&tomlstr).expect("Failed to parse toml");
-
-    let src = tomlval
-        .get("playlist_sources")
-        .and_then(|x| x.as_array())
-        .expect("No sources found");
+    let pagination = match crate::htmlgen::Pagination::from_url(&req.url()?) {
+        Ok(p) => p,
+        Err(e) => return Response::error(format!("Invalid pagination. {e}"), 400),
+    };
 
-    let names = src
-        .iter()
-        .map(|x| {
-            x.get("name")
-                .map(|x| x.as_str().expect("`name` value is not a string"))
-                .expect("`name` field missing")
-        })
-        .collect::<Vec<_>>();
+    // A fresh deployment without config just has no playlists yet
+    let config = match ctx
+        .data
+        .config(&ctx.env)
+        .await
+        .and_then(|x| x.playlist_opt())
+    {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
+    let names = config.map(|x| x.names()).unwrap_or_default();
 
     if as_html {
-        Response::from_html(
-            crate::htmlgen::gen_linkpage(
-                names
-                    .into_iter()
-                    .map(|x| crate::htmlgen::Nav::new(format!("playlist/{x}"), x))
-                    .collect_vec(),
-            )
-            .expect("Failed render template"),
-        )
+        Response::from_html(render_list(names, pagination))
     } else {
         Response::ok(names.join("\n"))
     }
 }
 
-pub async fn playlist_single(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+/// The `/playlist` HTML page for these playlist names
+fn render_list(names: Vec<&str>, pagination: crate::htmlgen::Pagination) -> String {
+    crate::htmlgen::gen_linkpage(
+        names
+            .into_iter()
+            .map(|x| crate::htmlgen::Nav::new(format!("playlist/{x}"), x))
+            .collect_vec(),
+        pagination,
+    )
+    .expect("Failed render template")
+}
+
+/// Every configured source with where it's scraped from. Auth-guarded, as
+/// the URLs of some sources aren't meant to be public.
+pub async fn playlist_sources(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
 
     let as_html = req
         .headers()
@@ -53,32 +66,168 @@ pub async fn playlist_single(req: Request, ctx: RouteContext<()>) -> Result<Resp
         .unwrap_or("".into())
         .contains("text/html");
 
-    let reversed = {
-        let url = req.url()?;
-        url.query_pairs().any(|(k, _)| k == "reversed")
+    let config = match ctx
+        .data
+        .config(&ctx.env)
+        .await
+        .and_then(|x| x.playlist_opt())
+    {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
     };
 
-    let tomlstr = kv.get("config_playlist").text().await?.unwrap_or("".into());
-    let tomlval = toml::from_str::<toml::Value>(&tomlstr).expect("Failed to parse toml");
-
-    let src = tomlval
-        .get("playlist_sources")
-        .and_then(|x| x.as_array())
-        .expect("No sources found");
-
-    let nameurlpair = src
-        .iter()
+    let sources = config
+        .into_iter()
+        .flat_map(|x| &x.playlist_sources)
         .map(|x| {
             (
-                x.get("name")
-                    .map(|x| x.as_str().expect("`name` value is not a string"))
-                    .expect("`name` field missing"),
-                x.get("url")
-                    .map(|x| x.as_str().expect("`url` value is not a string"))
-                    .expect("`url` field missing"),
+                x.name.as_str(),
+                x.url.as_str(),
+                crate::playlist::video_baseurl(&x.url),
             )
         })
-        .collect::<HashMap<_, _>>();
+        .collect_vec();
+
+    if !as_html {
+        return Response::from_json(
+            &sources
+                .iter()
+                .map(|(name, url, video_path)| {
+                    serde_json::json!({"name": name, "url": url, "video_path": video_path})
+                })
+                .collect_vec(),
+        );
+    }
+
+    Response::from_html(
+        rsx! {
+        <!DOCTYPE html><html>
+        <head><title>playlist sources</title></head>
+            <body>
+            <table>
+                <tr><th>"name"</th><th>"url"</th><th>"video path"</th></tr>
+                @for (name, url, video_path) in &sources {
+                    <tr><td>(name)</td><td>(url)</td><td>(video_path)</td></tr>
+                }
+            </table>
+            </body>
+        </html>
+                }
+        .render()
+        .as_inner(),
+    )
+}
+
+/// Sources `/playlist/all` scrapes at once by default. Each one fetches its
+/// pages concurrently too, so this multiplies quickly.
+pub const DEFAULT_MERGE_CONCURRENCY: usize = 3;
+
+/// The links of every playlist source merged into one list, in config order
+/// with repeats dropped. Sources failing to scrape are skipped and named in
+/// the `X-Failed-Sources` header.
+pub async fn playlist_all(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    let delimiter = match Delimiter::from_url(&req.url()?) {
+        Ok(d) => d,
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+
+    let appconfig = match ctx.data.config(&ctx.env).await {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
+    let config = match appconfig.playlist() {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
+
+    let concurrency = appconfig.playlist_merge_concurrency;
+    tracing::info!(
+        "Merging {} playlists, {concurrency} sources at a time with up to {} pages each",
+        config.playlist_sources.len(),
+        crate::playlist::PAGE_CONCURRENCY
+    );
+
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+    let sem = std::sync::Arc::new(async_lock::Semaphore::new(concurrency));
+    let ctx = &ctx;
+    let results = futures::future::join_all(config.playlist_sources.iter().map(|source| {
+        let (sem, kv) = (sem.clone(), kv.clone());
+        async move {
+            let _permit = sem.acquire().await;
+            // Cached results count, the same as for a single playlist
+            (source, source_urls(ctx, kv, source).await)
+        }
+    }))
+    .await;
+
+    let mut dedup = crate::links::Deduper::new(crate::links::DedupMode::Global);
+    let mut merged = Vec::new();
+    let mut failed = Vec::new();
+    for (source, urls) in results {
+        match urls {
+            Ok(mut urls) => {
+                if source.is_reversed(false) {
+                    urls.reverse();
+                }
+                merged.extend(urls.into_iter().filter(|x| dedup.keep(x)));
+            }
+            Err(e) => {
+                tracing::warn!("Leaving {} out of the merged playlist: {e}", source.name);
+                failed.push(source.name.as_str());
+            }
+        }
+    }
+
+    let mut res = Response::ok(merged.join(delimiter.as_str()))?;
+    if !failed.is_empty() {
+        res.headers_mut()
+            .set("X-Failed-Sources", &failed.join(","))?;
+    }
+    Ok(res)
+}
+
+pub async fn playlist_single(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+
+    let as_html = req
+        .headers()
+        .get("Accept")?
+        .unwrap_or("".into())
+        .contains("text/html");
+
+    let url = req.url()?;
+    let reversed = url.query_pairs().any(|(k, _)| k == "reversed");
+    let streamed = url.query_pairs().any(|(k, _)| k == "stream");
+    let include_thumbs = url.query_pairs().any(|(k, _)| k == "include_thumbs");
+    let relative = url
+        .query_pairs()
+        .any(|(k, v)| k == "relative" && (v == "1" || v == "true"));
+    let format = match url.query_pairs().find(|(k, _)| k == "format") {
+        Some((_, v)) => match v.parse::<PlaylistFormat>() {
+            Ok(f) => f,
+            Err(e) => return Response::error(e.to_string(), 400),
+        },
+        None => PlaylistFormat::default(),
+    };
+    let delimiter = match Delimiter::from_url(&url) {
+        Ok(d) => d,
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+    if url.query_pairs().any(|(k, _)| k == "since") {
+        return Response::error(
+            "Playlist sources are plain URLs without timestamps, `since` isn't available",
+            400,
+        );
+    }
+
+    let appconfig = match ctx.data.config(&ctx.env).await {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
+    let config = match appconfig.playlist() {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
 
     let playlistname = if let Some(n) = ctx.param("name") {
         n
@@ -86,28 +235,413 @@ pub async fn playlist_single(req: Request, ctx: RouteContext<()>) -> Result<Resp
         return Response::error("Playlist not found", 404);
     };
 
-    let url = nameurlpair
-        .get(playlistname.as_str())
-        .unwrap_or_else(|| panic!("Cannot get url for name {playlistname}"));
+    let Some(source) = config.get(playlistname) else {
+        return Response::error("Playlist not found", 404);
+    };
 
-    let playlist_urls = crate::playlist::PlaylistFetcher::new()
-        .get(url)
-        .await
-        .unwrap_or_else(|_| panic!("Failed getting urls for {playlistname}"));
+    // Thumbnails aren't kept in the result cache, so this always scrapes
+    if include_thumbs {
+        let entries = crate::playlist::PlaylistFetcher::for_source(source, &ctx.data.subrequests)
+            .get_entries(&source.url)
+            .await
+            .and_then(|x| source.check_min_videos(x.len()).map(|_| x));
+
+        return match entries {
+            Ok(mut entries) => {
+                if source.is_reversed(reversed) {
+                    entries.reverse();
+                }
+                if relative {
+                    for entry in &mut entries {
+                        entry.url = crate::playlist::relative_link(&entry.url, &source.url);
+                    }
+                }
+                Response::from_json(&entries)
+            }
+            Err(e) => Response::error(format!("Failed getting urls for {playlistname}. {e}"), 502),
+        };
+    }
+
+    // Nothing needs the whole list up front, so pipe the links out as pages resolve
+    if streamed
+        && !as_html
+        && format == PlaylistFormat::Text
+        && source.cache_ttl.is_none()
+        && source.min_videos == 0
+        && !source.is_reversed(reversed)
+    {
+        let source_url = source.url.clone();
+        let body = crate::playlist::PlaylistFetcher::for_source(source, &ctx.data.subrequests)
+            .stream(&source.url)
+            .map_ok(move |x| {
+                if relative {
+                    crate::playlist::relative_link(&x, &source_url)
+                } else {
+                    x
+                }
+            })
+            .map_ok(move |x| x + delimiter.as_str())
+            .map_err(|e| worker::Error::RustError(e.to_string()));
+        return Response::from_stream(body);
+    }
+
+    let scraped = source_urls(&ctx, kv, source).await;
 
-    let mut playlist_urls: Vec<&str> = playlist_urls.lines().map(str::trim).collect();
+    let mut playlist_urls = match scraped {
+        Ok(urls) => urls,
+        Err(e) => {
+            return Response::error(format!("Failed getting urls for {playlistname}. {e}"), 502);
+        }
+    };
 
-    if reversed {
+    if source.is_reversed(reversed) {
         playlist_urls.reverse();
     }
 
-    let playlist_urls = playlist_urls.join("\n");
+    // Absolute by default, `?relative=1` leaves the path on the source's origin
+    if relative {
+        playlist_urls = playlist_urls
+            .iter()
+            .map(|x| crate::playlist::relative_link(x, &source.url))
+            .collect();
+    }
+
+    if format != PlaylistFormat::Text {
+        let body = match format.render(playlistname, &playlist_urls) {
+            Ok(b) => b,
+            Err(e) => return Response::error(format!("Failed rendering {playlistname}. {e}"), 500),
+        };
+        let mut res = Response::from_bytes(body)?;
+        res.headers_mut()
+            .set("Content-Type", format.content_type())?;
+        res.headers_mut().set(
+            "Content-Disposition",
+            &format!(
+                "attachment; filename=\"{playlistname}.{}\"",
+                format.extension()
+            ),
+        )?;
+        return Ok(res);
+    }
+
+    // Plain text can be written line by line instead of joined up front.
+    // The HTML page and the other formats need the whole body, so they stay buffered.
+    if streamed && !as_html {
+        let body = futures::stream::iter(
+            playlist_urls
+                .into_iter()
+                .map(move |x| Ok::<_, worker::Error>(x + delimiter.as_str())),
+        );
+        return Response::from_stream(body);
+    }
 
     if as_html {
         Response::from_html(
-            crate::htmlgen::gen_plaintext(playlist_urls).expect("Failed render template"),
+            crate::htmlgen::gen_plaintext(
+                playlist_urls.join("\n"),
+                None,
+                appconfig.text_template.as_deref(),
+            )
+            .expect("Failed render template"),
         )
     } else {
-        Response::ok(playlist_urls)
+        Response::ok(playlist_urls.join(delimiter.as_str()))
+    }
+}
+
+/// The playlist as a `.txt` download, one link per line, whatever the
+/// `Accept` header says. `?reversed` and `?relative=1` apply as on `/playlist/:name`.
+pub async fn playlist_raw(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+
+    let url = req.url()?;
+    let reversed = url.query_pairs().any(|(k, _)| k == "reversed");
+    let relative = url
+        .query_pairs()
+        .any(|(k, v)| k == "relative" && (v == "1" || v == "true"));
+
+    let config = match ctx.data.config(&ctx.env).await.and_then(|x| x.playlist()) {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
+
+    let Some(source) = ctx.param("name").and_then(|x| config.get(x)) else {
+        return Response::error("Playlist not found", 404);
+    };
+
+    let mut playlist_urls = match source_urls(&ctx, kv, source).await {
+        Ok(urls) => urls,
+        Err(e) => {
+            return Response::error(format!("Failed getting urls for {}. {e}", source.name), 502);
+        }
+    };
+
+    if source.is_reversed(reversed) {
+        playlist_urls.reverse();
+    }
+    if relative {
+        playlist_urls = playlist_urls
+            .iter()
+            .map(|x| crate::playlist::relative_link(x, &source.url))
+            .collect();
+    }
+
+    let mut res = Response::ok(playlist_urls.join("\n"))?;
+    res.headers_mut()
+        .set("Content-Type", "text/plain; charset=utf-8")?;
+    res.headers_mut().set(
+        "Content-Disposition",
+        &format!("attachment; filename=\"{}.txt\"", source.name),
+    )?;
+    Ok(res)
+}
+
+/// The links of `source`, from its cached result when it has a fresh one,
+/// scraped otherwise. A stale result is served as is and refreshed in the
+/// background when the source allows it.
+async fn source_urls(
+    ctx: &RouteContext<AppData>,
+    kv: worker::KvStore,
+    source: &PlaylistSource,
+) -> anyhow::Result<Vec<String>> {
+    let name = &source.name;
+    let results = PlaylistResultCache::new(kv);
+    let cached = match source.cache_ttl {
+        Some(_) => results.get(name).await.unwrap_or_else(|e| {
+            tracing::warn!("Failed reading cached result for {name}: {e}");
+            None
+        }),
+        None => None,
+    };
+
+    match (source.cache_ttl, cached) {
+        (Some(ttl), Some(cached)) if cached.is_fresh(ttl) => {
+            tracing::trace!("Playlist result HIT for {name}");
+            Ok(cached.urls)
+        }
+        (Some(_), Some(cached)) if source.stale_while_revalidate => {
+            tracing::trace!("Playlist result STALE for {name}, refreshing in background");
+
+            let (results, source, subrequests) = (
+                results.clone(),
+                source.clone(),
+                ctx.data.subrequests.clone(),
+            );
+            ctx.data.worker_ctx.wait_until(async move {
+                if let Err(e) = results.refresh(&source, &subrequests).await {
+                    tracing::error!("Background refresh of {} failed: {e}", source.name);
+                }
+            });
+
+            Ok(cached.urls)
+        }
+        (Some(_), _) => {
+            tracing::trace!("Playlist result MISS for {name}");
+            results
+                .refresh(source, &ctx.data.subrequests)
+                .await
+                .map(|x| x.urls)
+        }
+        (None, _) => crate::playlist::PlaylistFetcher::for_source(source, &ctx.data.subrequests)
+            .get_links(&source.url)
+            .await
+            .and_then(|urls| source.check_min_videos(urls.len()).map(|_| urls)),
+    }
+}
+
+/// Scrape one page of `?url=` in isolation, to debug selector or prefix mismatches.
+/// `?name=` inspects a configured source instead, and so does a `?url=` that's
+/// one's URL, scraping with that source's settings. `?raw=1` returns the
+/// page's HTML instead, and needs auth.
+pub async fn playlist_page_inspect(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+
+    let page = match params.get("page").map(|x| x.parse::<u32>()) {
+        Some(Ok(p)) if p >= 1 => p,
+        None => 1,
+        _ => return Response::error("`page` must be a positive number", 400),
+    };
+    let raw = params.get("raw").is_some_and(|x| x == "1" || x == "true");
+
+    if raw && let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
     }
+
+    let config = match ctx.data.config(&ctx.env).await {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed loading config. {e}"), 500),
+    };
+
+    let sources = config.playlist().ok();
+    let (source_url, source) = match (params.get("name"), params.get("url")) {
+        (Some(name), _) => match sources.and_then(|x| x.get(name)) {
+            Some(source) => (&source.url, Some(source)),
+            None => return Response::error("Playlist not found", 404),
+        },
+        (None, Some(url)) => (
+            url,
+            sources.and_then(|x| x.playlist_sources.iter().find(|s| &s.url == url)),
+        ),
+        (None, None) => return Response::error("url key empty", 400),
+    };
+    if let Err(e) = config.url_guard.check(source_url) {
+        return Response::error(format!("Refusing to fetch. {e}"), 400);
+    }
+
+    let fetcher = match source {
+        Some(source) => crate::playlist::PlaylistFetcher::for_source(source, &ctx.data.subrequests),
+        None => crate::playlist::PlaylistFetcher::new(&ctx.data.subrequests),
+    };
+    let (html, links) = match fetcher
+        .with_url_guard(config.url_guard.clone())
+        .inspect_page(source_url, page)
+        .await
+    {
+        Ok(x) => x,
+        Err(e) if e.is::<crate::fetcher::RedirectRefused>() => {
+            return Response::error(format!("Refusing to fetch. {e}"), 400);
+        }
+        Err(e) => return Response::error(format!("GET request failed. {e}"), 502),
+    };
+
+    if raw {
+        let mut res = Response::ok(html)?;
+        res.headers_mut()
+            .set("Content-Type", "text/plain; charset=utf-8")?;
+        return Ok(res);
+    }
+
+    Response::from_json(&serde_json::json!({
+        "url": source_url,
+        "source": source.map(|x| &x.name),
+        "page": page,
+        "count": links.len(),
+        "links": links,
+    }))
+}
+
+/// Re-scrape a playlist now and store the result, whatever its `cache_ttl`
+pub async fn playlist_refresh(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+
+    let config = match ctx.data.config(&ctx.env).await.and_then(|x| x.playlist()) {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
+
+    let Some(source) = ctx.param("name").and_then(|x| config.get(x)) else {
+        return Response::error("Playlist not found", 404);
+    };
+
+    match PlaylistResultCache::new(kv)
+        .refresh(source, &ctx.data.subrequests)
+        .await
+    {
+        Ok(entry) => Response::from_json(&serde_json::json!({
+            "name": source.name,
+            "count": entry.urls.len(),
+            "fetched_at": entry.fetched_at,
+        })),
+        Err(e) => Response::error(format!("Failed refreshing {}. {e}", source.name), 502),
+    }
+}
+
+/// Re-scrape a playlist and list what changed since its cached result.
+/// The fresh result replaces the cached one, so the next diff starts from here.
+pub async fn playlist_diff(req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    if let Some(res) = crate::auth::reject_unauthorized(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    let kv = ctx.env.kv("VID_PLAYLIST_MANAGER_KV")?;
+
+    let config = match ctx.data.config(&ctx.env).await.and_then(|x| x.playlist()) {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Failed to load playlist config. {e}"), 500),
+    };
+
+    let Some(source) = ctx.param("name").and_then(|x| config.get(x)) else {
+        return Response::error("Playlist not found", 404);
+    };
+
+    let results = PlaylistResultCache::new(kv);
+    let prev = match results.get(&source.name).await {
+        Ok(p) => p,
+        Err(e) => return Response::error(format!("Failed reading cached result. {e}"), 500),
+    };
+
+    let fresh = match results.refresh(source, &ctx.data.subrequests).await {
+        Ok(entry) => entry,
+        Err(e) => return Response::error(format!("Failed refreshing {}. {e}", source.name), 502),
+    };
+
+    // Nothing to compare against on the first run
+    let (added, removed) = match prev {
+        Some(prev) => {
+            let prev_set = prev.urls.iter().collect::<HashSet<_>>();
+            let fresh_set = fresh.urls.iter().collect::<HashSet<_>>();
+
+            (
+                fresh
+                    .urls
+                    .iter()
+                    .filter(|x| !prev_set.contains(x))
+                    .cloned()
+                    .collect_vec(),
+                prev.urls
+                    .iter()
+                    .filter(|x| !fresh_set.contains(x))
+                    .cloned()
+                    .collect_vec(),
+            )
+        }
+        None => (vec![], vec![]),
+    };
+
+    Response::from_json(&serde_json::json!({
+        "name": source.name,
+        "added": added,
+        "removed": removed,
+    }))
+}
+
+/// Parse and validate a `config_playlist` TOML body without storing it
+pub async fn playlist_config_validate(
+    mut req: Request,
+    _ctx: RouteContext<AppData>,
+) -> Result<Response> {
+    let body = req.text().await?;
+
+    match PlaylistConfig::from_toml(&body).and_then(|x| x.validate().map(|_| x)) {
+        Ok(config) => Response::from_json(&serde_json::json!({
+            "valid": true,
+            "sources": config.playlist_sources.len(),
+        })),
+        Err(e) => Ok(Response::from_json(&serde_json::json!({
+            "valid": false,
+            "error": e.to_string(),
+        }))?
+        .with_status(400)),
+    }
+}
+
+/// Render the `/playlist` page a TOML config would give, without storing it
+pub async fn playlist_preview(mut req: Request, _ctx: RouteContext<AppData>) -> Result<Response> {
+    let pagination = match crate::htmlgen::Pagination::from_url(&req.url()?) {
+        Ok(p) => p,
+        Err(e) => return Response::error(format!("Invalid pagination. {e}"), 400),
+    };
+
+    let body = req.text().await?;
+    let config = match PlaylistConfig::from_toml(&body).and_then(|x| x.validate().map(|_| x)) {
+        Ok(c) => c,
+        Err(e) => return Response::error(format!("Invalid playlist config. {e}"), 400),
+    };
+
+    Response::from_html(render_list(config.names(), pagination))
 }
@@ -0,0 +1,60 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use async_lock::Semaphore;
+
+/// Open subrequests Cloudflare allows at once per invocation
+const DEFAULT_MAX_CONCURRENT: usize = 6;
+
+/// The subrequests of one invocation: how many may be open at once, and how
+/// many were sent so far.
+///
+/// Made once per request (see `AppData`) or cron run, and handed to every
+/// `fetcher::Client` of it, which holds a permit for each attempt and counts
+/// it. Playlist pages and Discord channels keep their own task-level
+/// concurrency (`PAGE_CONCURRENCY`, the cron job's channel semaphore), but
+/// however many scrapes and channels run at once, the open subrequests stay
+/// within `SUBREQUEST_CONCURRENCY`. Concurrent invocations don't share it,
+/// as Cloudflare counts each of them on its own.
+#[derive(Clone, Debug)]
+pub struct Subrequests {
+    limiter: Arc<Semaphore>,
+    sent: Arc<AtomicUsize>,
+}
+
+impl Subrequests {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            limiter: Arc::new(Semaphore::new(permits)),
+            sent: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Sized from `SUBREQUEST_CONCURRENCY`
+    pub fn from_env(env: &worker::Env) -> Self {
+        let permits = env
+            .var("SUBREQUEST_CONCURRENCY")
+            .ok()
+            .and_then(|x| x.to_string().parse::<usize>().ok())
+            .filter(|x| *x > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT);
+
+        Self::new(permits)
+    }
+
+    pub fn limiter(&self) -> Arc<Semaphore> {
+        self.limiter.clone()
+    }
+
+    /// Counter every attempt is added to, see `fetcher::Client::with_attempt_counter`
+    pub fn counter(&self) -> Arc<AtomicUsize> {
+        self.sent.clone()
+    }
+
+    /// Subrequests sent so far, retries included
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,26 @@
+use tracing::Span;
+use web_time::Instant;
+
+/// Times the span it's started in. On drop, the time taken is recorded as
+/// the span's `elapsed_ms` field, which the span has to declare, and logged.
+pub struct SpanTimer {
+    span: Span,
+    started: Instant,
+}
+
+impl SpanTimer {
+    pub fn start() -> Self {
+        Self {
+            span: Span::current(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for SpanTimer {
+    fn drop(&mut self) {
+        let elapsed_ms = self.started.elapsed().as_millis() as u64;
+        self.span.record("elapsed_ms", elapsed_ms);
+        tracing::debug!(parent: &self.span, elapsed_ms, "Finished in {elapsed_ms}ms");
+    }
+}
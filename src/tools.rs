@@ -0,0 +1,55 @@
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::AppData;
+use crate::discord::utils;
+
+/// The time a snowflake id was minted at, for checking watermarks and ranges
+pub async fn snowflake_to_time(_req: Request, ctx: RouteContext<AppData>) -> Result<Response> {
+    let Some(id) = ctx.param("id") else {
+        return Response::error("Missing snowflake id", 400);
+    };
+
+    let (ms, time) = match utils::snowflake_to_unix_ms(id)
+        .and_then(|ms| Ok((ms, utils::snowflake_to_utc_datetime(id)?)))
+    {
+        Ok(x) => x,
+        Err(e) => return Response::error(format!("Invalid snowflake. {e}"), 400),
+    };
+
+    let rfc3339 = match time.format(&time::format_description::well_known::Rfc3339) {
+        Ok(t) => t,
+        Err(e) => return Response::error(format!("Failed formatting time. {e}"), 500),
+    };
+
+    Response::from_json(&serde_json::json!({
+        "id": id,
+        "unix_ms": ms,
+        "time": rfc3339,
+    }))
+}
+
+/// The lowest snowflake of `?ts=` (RFC3339), as used for `before`/`after` queries
+pub async fn time_to_snowflake(req: Request, _ctx: RouteContext<AppData>) -> Result<Response> {
+    let url = req.url()?;
+    let Some((_, ts)) = url.query_pairs().find(|(k, _)| k == "ts") else {
+        return Response::error("Missing `ts`", 400);
+    };
+
+    let time =
+        match time::OffsetDateTime::parse(&ts, &time::format_description::well_known::Rfc3339) {
+            Ok(t) => t,
+            Err(e) => {
+                return Response::error(format!("`ts` must be an RFC3339 timestamp: {e}"), 400);
+            }
+        };
+
+    let ms = (time.unix_timestamp_nanos() / 1_000_000) as i64;
+    match utils::unix_ms_to_snowflake(ms, 0, 0) {
+        Ok(id) => Response::from_json(&serde_json::json!({
+            "time": ts,
+            "unix_ms": ms,
+            "id": id,
+        })),
+        Err(e) => Response::error(format!("Can't encode `ts`. {e}"), 400),
+    }
+}